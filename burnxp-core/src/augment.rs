@@ -0,0 +1,293 @@
+use anyhow::{ensure, Result};
+use burn::prelude::*;
+use image::{imageops, imageops::colorops, DynamicImage, Rgb, RgbImage};
+use rand::{seq::SliceRandom, thread_rng, Rng};
+use rand_distr::{Beta, Distribution};
+
+/// Random augmentations applied to the training images.
+/// Each one is disabled by its neutral value, e.g. 0 for probabilities and magnitudes
+#[derive(Config, Debug)]
+pub struct AugmentConfig {
+    /// Probability of flipping horizontally
+    #[config(default = 0.5)]
+    flip: f64,
+    /// Minimal area ratio kept by the random resized crop, 1 to disable
+    #[config(default = 1.0)]
+    crop_scale: f64,
+    /// Maximal aspect ratio change of the random resized crop
+    #[config(default = "4. / 3.")]
+    crop_ratio: f64,
+    /// Maximal rotation in degrees, bilinear with reflect padding
+    #[config(default = 30.0)]
+    rotation: f32,
+    /// Maximal brightness shift, in 0..255
+    #[config(default = 32)]
+    brightness: i32,
+    /// Maximal contrast change in percent
+    #[config(default = 0.0)]
+    contrast: f32,
+    /// Maximal saturation change, the saturation is scaled by 1 ± saturation
+    #[config(default = 0.0)]
+    saturation: f32,
+    /// Maximal hue rotation in degrees
+    #[config(default = 0)]
+    hue: i32,
+    /// Maximal sigma of the gaussian blur
+    #[config(default = 0.0)]
+    blur: f32,
+    /// Probability of converting to grayscale
+    #[config(default = 0.0)]
+    grayscale: f64,
+    /// Alpha of the Beta distribution sampling the Mixup ratio, 0 to disable
+    #[config(default = 0.0)]
+    mixup: f64,
+    /// Alpha of the Beta distribution sampling the CutMix ratio, 0 to disable
+    #[config(default = 0.0)]
+    cutmix: f64,
+    /// Probability of mixing a batch, Mixup and CutMix are chosen evenly if both enabled
+    #[config(default = 1.0)]
+    mix_prob: f64,
+}
+
+impl AugmentConfig {
+    /// Check the probabilities and ranges, which would otherwise panic or produce NaN
+    /// while training
+    pub fn validate(&self) -> Result<()> {
+        for (name, p) in [
+            ("flip", self.flip),
+            ("grayscale", self.grayscale),
+            ("mix_prob", self.mix_prob),
+        ] {
+            ensure!(
+                (0. ..=1.).contains(&p),
+                "expected {} to be in [0, 1], got {}",
+                name,
+                p
+            );
+        }
+        ensure!(
+            self.crop_scale > 0. && self.crop_scale <= 1.,
+            "expected crop_scale to be in (0, 1], got {}",
+            self.crop_scale
+        );
+        ensure!(
+            self.crop_ratio > 0. && self.crop_ratio.is_finite(),
+            "expected crop_ratio to be positive, got {}",
+            self.crop_ratio
+        );
+        ensure!(
+            (0..=255).contains(&self.brightness),
+            "expected brightness to be in [0, 255], got {}",
+            self.brightness
+        );
+        ensure!(
+            (0. ..=1.).contains(&self.saturation),
+            "expected saturation to be in [0, 1], got {}",
+            self.saturation
+        );
+        ensure!(
+            self.hue >= 0,
+            "expected hue to be non-negative, got {}",
+            self.hue
+        );
+        for (name, v) in [
+            ("rotation", self.rotation),
+            ("contrast", self.contrast),
+            ("blur", self.blur),
+        ] {
+            ensure!(
+                v >= 0. && v.is_finite(),
+                "expected {} to be non-negative, got {}",
+                name,
+                v
+            );
+        }
+        for (name, alpha) in [("mixup", self.mixup), ("cutmix", self.cutmix)] {
+            ensure!(
+                alpha >= 0. && alpha.is_finite(),
+                "expected {} to be non-negative, got {}",
+                name,
+                alpha
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether Mixup or CutMix is enabled
+    pub(crate) fn mixes(&self) -> bool {
+        self.mixup > 0. || self.cutmix > 0.
+    }
+
+    /// Whether `crop` may change the image, otherwise the resized image can be cached
+    pub(crate) fn crops(&self) -> bool {
+        self.crop_scale < 1.
+    }
+
+    /// Random resized crop, applied to the original image before resizing to keep the details
+    pub(crate) fn crop(&self, img: DynamicImage, rng: &mut impl Rng) -> DynamicImage {
+        if self.crop_scale >= 1. {
+            return img;
+        }
+        let (width, height) = (img.width() as f64, img.height() as f64);
+        let log_ratio = self.crop_ratio.ln().abs();
+        // retry if the crop is out of the image, like torchvision does
+        for _ in 0..10 {
+            let area = width * height * rng.gen_range(self.crop_scale..1.);
+            let ratio = rng.gen_range(-log_ratio..=log_ratio).exp();
+            let (w, h) = ((area * ratio).sqrt(), (area / ratio).sqrt());
+            if (1. ..=width).contains(&w) && (1. ..=height).contains(&h) {
+                let x = rng.gen_range(0. ..=width - w);
+                let y = rng.gen_range(0. ..=height - h);
+                return img.crop_imm(x as u32, y as u32, w as u32, h as u32);
+            }
+        }
+        img
+    }
+
+    /// Geometric and color augmentations of the resized image
+    pub(crate) fn apply(&self, img: DynamicImage, rng: &mut impl Rng) -> RgbImage {
+        let mut img = img.to_rgb8();
+        if rng.gen_bool(self.flip) {
+            imageops::flip_horizontal_in_place(&mut img);
+        }
+        if self.rotation > 0. {
+            let degree: f32 = rng.gen_range(-self.rotation..self.rotation);
+            img = rotate_reflect(&img, degree.to_radians());
+        }
+        if self.brightness > 0 {
+            colorops::brighten_in_place(&mut img, rng.gen_range(-self.brightness..self.brightness));
+        }
+        if self.contrast > 0. {
+            colorops::contrast_in_place(&mut img, rng.gen_range(-self.contrast..self.contrast));
+        }
+        if self.saturation > 0. {
+            saturate_in_place(
+                &mut img,
+                1. + rng.gen_range(-self.saturation..self.saturation),
+            );
+        }
+        if self.hue > 0 {
+            colorops::huerotate_in_place(&mut img, rng.gen_range(-self.hue..self.hue));
+        }
+        if self.blur > 0. {
+            let sigma: f32 = rng.gen_range(0. ..self.blur);
+            // too small to be visible
+            if sigma > 0.1 {
+                img = imageops::blur(&img, sigma);
+            }
+        }
+        if rng.gen_bool(self.grayscale) {
+            img = DynamicImage::ImageLuma8(imageops::grayscale(&img)).to_rgb8();
+        }
+        img
+    }
+
+    /// Mixup or CutMix the batch with a shuffled copy of itself, blending the targets
+    /// into soft ones
+    /// # Shapes
+    ///   - Images [batch_size, 3, size, size]
+    ///   - Targets [batch_size, num_classes]
+    pub(crate) fn mix<B: Backend>(
+        &self,
+        datas: Tensor<B, 4>,
+        targets: Tensor<B, 2>,
+    ) -> (Tensor<B, 4>, Tensor<B, 2>) {
+        let mut rng = thread_rng();
+        let alphas = [self.mixup, self.cutmix];
+        let enabled = alphas.iter().filter(|a| **a > 0.).count();
+        if enabled == 0 || !rng.gen_bool(self.mix_prob) {
+            return (datas, targets);
+        }
+        let cutmix = match enabled {
+            1 => self.cutmix > 0.,
+            _ => rng.gen_bool(0.5),
+        };
+        let alpha = alphas[cutmix as usize];
+        let lambda = Beta::new(alpha, alpha)
+            .expect("Mixup and CutMix alpha should be positive")
+            .sample(&mut rng) as f32;
+
+        let [batch_size, _, height, width] = datas.dims();
+        let device = datas.device();
+        let mut perm = (0..batch_size as i32).collect::<Vec<_>>();
+        perm.shuffle(&mut rng);
+        let perm = Tensor::<B, 1, Int>::from_ints(&perm[..], &device);
+        let shuffled_datas = datas.clone().select(0, perm.clone());
+        let shuffled_targets = targets.clone().select(0, perm);
+
+        let (datas, lambda) = if cutmix {
+            // a box covering 1 - lambda of the image is pasted from the shuffled one
+            let ratio = (1. - lambda).sqrt();
+            let (h, w) = (
+                (height as f32 * ratio) as usize,
+                (width as f32 * ratio) as usize,
+            );
+            if h == 0 || w == 0 {
+                return (datas, targets);
+            }
+            let y = rng.gen_range(0..=height - h);
+            let x = rng.gen_range(0..=width - w);
+            let mask = Tensor::<B, 4>::zeros([1, 1, height, width], &device).slice_assign(
+                [0..1, 0..1, y..y + h, x..x + w],
+                Tensor::ones([1, 1, h, w], &device),
+            );
+            let datas = datas * (mask.clone().neg() + 1.) + shuffled_datas * mask;
+            (datas, 1. - (h * w) as f32 / (height * width) as f32)
+        } else {
+            (datas * lambda + shuffled_datas * (1. - lambda), lambda)
+        };
+        (datas, targets * lambda + shuffled_targets * (1. - lambda))
+    }
+}
+
+/// Rotate about the center with bilinear interpolation, filling the corners by reflection
+fn rotate_reflect(img: &RgbImage, theta: f32) -> RgbImage {
+    let (width, height) = img.dimensions();
+    let (cx, cy) = ((width as f32 - 1.) / 2., (height as f32 - 1.) / 2.);
+    let (sin, cos) = theta.sin_cos();
+    RgbImage::from_fn(width, height, |x, y| {
+        let (dx, dy) = (x as f32 - cx, y as f32 - cy);
+        let sx = reflect(cos * dx + sin * dy + cx, width);
+        let sy = reflect(-sin * dx + cos * dy + cy, height);
+        bilinear(img, sx, sy)
+    })
+}
+
+/// Reflect the coordinate into [0, len - 1]
+fn reflect(v: f32, len: u32) -> f32 {
+    let max = len.saturating_sub(1) as f32;
+    if max == 0. {
+        return 0.;
+    }
+    let v = v.rem_euclid(2. * max);
+    if v > max {
+        2. * max - v
+    } else {
+        v
+    }
+}
+
+fn bilinear(img: &RgbImage, x: f32, y: f32) -> Rgb<u8> {
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = (
+        (x0 + 1).min(img.width() - 1),
+        (y0 + 1).min(img.height() - 1),
+    );
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+    let [a, b, c, d] = [(x0, y0), (x1, y0), (x0, y1), (x1, y1)].map(|(x, y)| img.get_pixel(x, y).0);
+    Rgb(std::array::from_fn(|i| {
+        let top = a[i] as f32 * (1. - fx) + b[i] as f32 * fx;
+        let bottom = c[i] as f32 * (1. - fx) + d[i] as f32 * fx;
+        (top * (1. - fy) + bottom * fy).round() as u8
+    }))
+}
+
+/// Scale the distance of each pixel to its gray level
+fn saturate_in_place(img: &mut RgbImage, factor: f32) {
+    for Rgb(pixel) in img.pixels_mut() {
+        let gray = 0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32;
+        for c in pixel.iter_mut() {
+            *c = (gray + (*c as f32 - gray) * factor).clamp(0., 255.) as u8;
+        }
+    }
+}
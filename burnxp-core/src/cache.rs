@@ -0,0 +1,122 @@
+use crate::data::open_image_resize;
+use memmap2::Mmap;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fs::File,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::UNIX_EPOCH,
+};
+use tagger::DataSetDesc;
+
+/// On-disk cache of the resized and letterboxed RGB buffers, keyed by the SHA-256 of the
+/// canonical path, the modification time, the file size and the input size, so that an edited
+/// image is processed again
+#[derive(Debug, Clone)]
+pub(crate) struct ImageCache {
+    dir: PathBuf,
+}
+
+/// A cached buffer mapped in memory, or a freshly processed one
+pub(crate) enum Buffer {
+    Mapped(Mmap),
+    Processed(Vec<u8>),
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Mapped(mmap) => mmap,
+            Self::Processed(buf) => buf,
+        }
+    }
+}
+
+impl ImageCache {
+    pub(crate) fn new(dir: PathBuf) -> Self {
+        std::fs::create_dir_all(&dir).ok();
+        Self { dir }
+    }
+
+    fn entry(&self, path: &Path, size: usize) -> Option<PathBuf> {
+        let path = path.canonicalize().ok()?;
+        let metadata = path.metadata().ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        // a specified digest, so that the entries stay valid across builds of burnxp
+        let mut hasher = Sha256::new();
+        hasher.update(path.as_os_str().as_encoded_bytes());
+        hasher.update(modified.as_secs().to_le_bytes());
+        hasher.update(modified.subsec_nanos().to_le_bytes());
+        hasher.update(metadata.len().to_le_bytes());
+        hasher.update((size as u64).to_le_bytes());
+        let name = format!("{:x}-{}.rgb", hasher.finalize(), size);
+        Some(self.dir.join(name))
+    }
+
+    /// The size x size x 3 RGB buffer of the image, processed and cached on a miss
+    pub(crate) fn load(&self, path: &Path, size: usize) -> Option<Buffer> {
+        let entry = self.entry(path, size)?;
+        if let Some(mmap) = map(&entry, size) {
+            return Some(Buffer::Mapped(mmap));
+        }
+        let buf = open_image_resize(path, size)?.to_rgb8().into_raw();
+        // write to a temporary file first, so that a mapped entry is never modified;
+        // failing to cache is not fatal
+        let tmp = entry.with_extension(format!("{:016x}.tmp", rand::random::<u64>()));
+        if std::fs::write(&tmp, &buf).is_err() || std::fs::rename(&tmp, &entry).is_err() {
+            std::fs::remove_file(&tmp).ok();
+        }
+        Some(Buffer::Processed(buf))
+    }
+}
+
+fn map(entry: &Path, size: usize) -> Option<Mmap> {
+    let file = File::open(entry).ok()?;
+    // SAFETY: entries are only created by renaming, never modified in place
+    let mmap = unsafe { Mmap::map(&file) }.ok()?;
+    (mmap.len() == size * size * 3).then_some(mmap)
+}
+
+/// Preprocess all the images of the data sets produced by `tagger divide` into the cache
+pub fn build_cache(cache_dir: PathBuf, sets: Vec<PathBuf>, size: usize, num_workers: usize) {
+    let cache = ImageCache::new(cache_dir);
+    let paths = sets
+        .into_iter()
+        .flat_map(|set| {
+            let desc: DataSetDesc = serde_json::from_reader(
+                File::open(&set).expect("Data set file should be accessible"),
+            )
+            .expect("Data set file should be legal");
+            desc.binary_encodings.into_values().flatten()
+        })
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let failed = AtomicUsize::new(0);
+    std::thread::scope(|s| {
+        for chunk in paths.chunks(paths.len().div_ceil(num_workers.max(1)).max(1)) {
+            s.spawn(|| {
+                for path in chunk {
+                    if cache.load(path, size).is_none() {
+                        eprintln!("Failed to load image {}", path.display());
+                        failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    let failed = failed.into_inner();
+    println!("Cached {} of {} images", paths.len() - failed, paths.len());
+}
+
+/// Remove the cache directory
+pub fn clean_cache(cache_dir: PathBuf) {
+    match std::fs::remove_dir_all(&cache_dir) {
+        Ok(()) => println!("Removed {}", cache_dir.display()),
+        Err(e) => eprintln!("Failed to remove {}: {}", cache_dir.display(), e),
+    }
+}
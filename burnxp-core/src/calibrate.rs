@@ -0,0 +1,175 @@
+use crate::{
+    data::{ImageBatcher, ImageDataSet, SkipReport},
+    eval::{Inference, TagMetrics},
+    ModelConfig, ModelType,
+};
+use burn::{config::Config, data::dataloader::DataLoaderBuilder, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+};
+use tagger::DataSetDesc;
+
+#[derive(Config, Debug)]
+pub struct CalibrateConfig {
+    checkpoint: PathBuf,
+    valid_set: PathBuf,
+    output: PathBuf,
+    /// Overrides the backbone saved in `model_config.json`
+    model: Option<ModelType>,
+    /// Overrides the input size saved in `model_config.json`, required without it
+    size: Option<usize>,
+    /// Choose the lowest threshold reaching this precision instead of maximizing F1
+    target_precision: Option<f32>,
+    #[config(default = 32)]
+    batch_size: usize,
+    #[config(default = 8)]
+    num_workers: usize,
+}
+
+/// Per-tag decision thresholds, a tag is predicted if its possibility is greater than the threshold
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Thresholds {
+    pub thresholds: HashMap<String, f32>,
+}
+
+impl Thresholds {
+    pub(crate) fn load(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+
+    /// Thresholds in the order of `all_tags`, `default` is used for tags not calibrated
+    pub(crate) fn for_tags(&self, all_tags: &[(String, i64)], default: f32) -> Vec<f32> {
+        all_tags
+            .iter()
+            .map(|(k, _)| self.thresholds.get(k).copied().unwrap_or(default))
+            .collect()
+    }
+}
+
+pub fn calibrate<B: Backend>(config: CalibrateConfig, devices: Vec<B::Device>) {
+    let valid_input: DataSetDesc = serde_json::from_reader(
+        File::open(config.valid_set).expect("Validation set file should be accessible"),
+    )
+    .expect("Validation set file should be legal");
+    assert!(
+        valid_input.tags.len() == valid_input.num_classes,
+        "expected tag names in the validation set, please divide again with the latest tagger"
+    );
+    let num_classes = valid_input.num_classes;
+    let tag_names = valid_input.tags.clone();
+    let model_config = ModelConfig::for_checkpoint(&config.checkpoint, config.model, config.size)
+        .expect("Model config should be found");
+    let size = model_config.size;
+    let model = model_config
+        .init::<B>(&devices[0], num_classes)
+        .load_checkpoint(config.checkpoint, &devices[0])
+        .expect("Failed to load checkpoint");
+    let dataloader_valid = DataLoaderBuilder::new(ImageBatcher::new(size))
+        .batch_size(config.batch_size)
+        .num_workers(config.num_workers)
+        .build(
+            ImageDataSet::valid(valid_input, size, SkipReport::new(None))
+                .expect("Validation set faild to be loaded"),
+        );
+
+    let inference = Inference::run(&model, dataloader_valid, num_classes);
+    let mut thresholds = Thresholds::default();
+    for (i, name) in tag_names.into_iter().enumerate() {
+        let (possibilities, targets) = inference.column(i);
+        let threshold = best_threshold(&possibilities, &targets, config.target_precision);
+        let metrics = TagMetrics::new(name.clone(), &possibilities, &targets, threshold);
+        println!(
+            "{}: threshold {:.3} precision {:.3} recall {:.3} f1 {:.3}",
+            name, threshold, metrics.precision, metrics.recall, metrics.f1
+        );
+        thresholds.thresholds.insert(name, threshold);
+    }
+    serde_json::to_writer_pretty(
+        File::create(config.output).expect("Thresholds file should be created"),
+        &thresholds,
+    )
+    .expect("Thresholds should be saved successfully");
+}
+
+/// Sweep the thresholds between the sorted possibilities, maximizing F1,
+/// or maximizing recall while the precision reaches the target
+fn best_threshold(possibilities: &[f32], targets: &[bool], target_precision: Option<f32>) -> f32 {
+    let mut ranked = possibilities.iter().zip(targets).collect::<Vec<_>>();
+    ranked.sort_by(|a, b| b.0.total_cmp(a.0));
+    let positives = targets.iter().filter(|t| **t).count() as f32;
+    // predicting none scores 0, kept for a tag without positives
+    let (mut best, mut best_threshold) = (0., 1.);
+    let mut tp = 0.;
+    for (k, (p, t)) in ranked.iter().enumerate() {
+        if **t {
+            tp += 1.;
+        }
+        // the top k + 1 samples are predicted positive
+        let threshold = match ranked.get(k + 1) {
+            Some((next, _)) if *next == *p => continue,
+            Some((next, _)) => (**next + **p) / 2.,
+            None => 0.,
+        };
+        let precision = tp / (k + 1) as f32;
+        let recall = if positives == 0. { 0. } else { tp / positives };
+        let value = match target_precision {
+            Some(target) if precision >= target => 1. + recall,
+            // fall back to the highest precision if the target is never reached
+            Some(_) => precision,
+            None if precision + recall == 0. => 0.,
+            None => 2. * precision * recall / (precision + recall),
+        };
+        if value > best {
+            best = value;
+            best_threshold = threshold;
+        }
+    }
+    best_threshold
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-6, "{} != {}", value, expected);
+    }
+
+    #[test]
+    fn best_f1() {
+        // F1 of 2/3, 1/2, 4/5 and 2/3 for the top 1 to 4
+        let threshold = best_threshold(&[0.9, 0.8, 0.7, 0.6], &[true, false, true, false], None);
+        assert_close(threshold, 0.65);
+    }
+
+    #[test]
+    fn ties() {
+        let possibilities = [0.9, 0.5, 0.5, 0.1];
+        let first = best_threshold(&possibilities, &[true, true, false, false], None);
+        let second = best_threshold(&possibilities, &[true, false, true, false], None);
+        // the tied samples are never split by the threshold
+        assert_close(first, 0.3);
+        assert_close(second, first);
+    }
+
+    #[test]
+    fn all_negative() {
+        assert_close(best_threshold(&[0.9, 0.1], &[false, false], None), 1.);
+        assert_close(best_threshold(&[0.9, 0.1], &[false, false], Some(0.5)), 1.);
+    }
+
+    #[test]
+    fn target_precision() {
+        let possibilities = [0.9, 0.8, 0.7, 0.6];
+        // the precision stays above 2/3 down to the last sample
+        let threshold = best_threshold(&possibilities, &[true, true, false, true], Some(0.6));
+        assert_close(threshold, 0.);
+        // the highest precision is 1/2 with the top 2 if the target is unreachable
+        let threshold = best_threshold(&possibilities, &[false, true, false, true], Some(0.9));
+        assert_close(threshold, 0.75);
+    }
+}
@@ -1,6 +1,11 @@
-use crate::{predict, train, ModelConfig, Output, PredictConfig, ResNetType, TrainingConfig};
-use burn::{backend::Autodiff, optim::AdamConfig};
-use clap::{CommandFactory as _, Parser, Subcommand};
+use crate::{
+    build_cache, calibrate, clean_cache, embed, predict, resume as resume_train, serve, sweep,
+    train, train_folds, AugmentConfig, BestMetric, CalibrateConfig, DivideConfig, EmbedConfig,
+    LossConfig, LossType, ModelConfig, ModelType, OptimizerConfig, OptimizerType, Output,
+    PredictConfig, ScheduleConfig, ScheduleType, ServeConfig, SweepConfig, TrainingConfig,
+};
+use burn::backend::Autodiff;
+use clap::{builder::RangedU64ValueParser, CommandFactory as _, Parser, Subcommand};
 use clap_complete::{generate, Shell};
 use std::path::PathBuf;
 
@@ -13,28 +18,68 @@ struct Cli {
 
 #[derive(Debug, Subcommand)]
 enum SubCmd {
-    /// Train a ResNet model with scores produced by the tagger.
+    /// Train a model with scores produced by the tagger.
     Train {
         /// Model type
-        #[arg(short, long, default_value=ResNetType::default())]
-        model: ResNetType,
+        #[arg(short, long, default_value=ModelType::default())]
+        model: ModelType,
+        /// Input size, images are resized to size x size, 720 by default and 224 for vit-tiny.
+        /// It is saved with the model and read back when predicting
+        #[arg(long)]
+        size: Option<usize>,
         /// Path to the training set json file produced by the tagger divide
         #[arg(short, long, default_value = "train.json")]
         train_set: PathBuf,
         /// Path to the validation set json file produced by the tagger divide
         #[arg(short, long, default_value = "valid.json")]
         valid_set: PathBuf,
-        /// Directory to save artifacts (The directory will be recreated if it exists)
+        /// Path to the tags produced by the tagger tag, divided with the seed into `train.json`
+        /// and `valid.json` of the artifact directory instead of reading the sets
+        #[arg(long, conflicts_with_all = ["train_set", "valid_set", "resume"])]
+        tags: Option<PathBuf>,
+        /// The ratio of the training set when dividing the tags
+        #[arg(long, default_value = "9", requires = "tags")]
+        train_ratio: u32,
+        /// The ratio of the validation set when dividing the tags
+        #[arg(long, default_value = "1", requires = "tags")]
+        valid_ratio: u32,
+        /// Directory to save artifacts
+        /// (The directory will be recreated if it exists, unless `--resume`)
         #[arg(short, long, default_value = "burnxp_artifact")]
         artifact_dir: PathBuf,
+        /// Resume from the latest checkpoint in the artifact directory with its
+        /// `train_config.json`, so the options saved in it can't be given
+        #[arg(long, conflicts_with_all = [
+            "model", "size", "train_set", "valid_set", "num_epochs", "batch_size",
+            "grad_accumulation", "num_workers", "learning_rate", "early_stopping", "pretrained",
+            "download_pretrained", "seed", "confidence_threshold", "optimizer", "weight_decay",
+            "momentum", "nesterov", "schedule", "warmup_epochs", "step_epochs", "step_gamma",
+            "freeze_epochs", "backbone_lr_factor", "ema_decay", "best_metric", "loss", "smoothing",
+            "focal_gamma", "focal_alpha", "asl_gamma_pos", "asl_gamma_neg", "asl_clip", "augment",
+            "cache_dir",
+        ])]
+        resume: bool,
+        /// K-fold cross-validation, training one model per fold in `fold{i}` of the artifact
+        /// directory with the sets suffixed by `-fold{i}` from `tagger divide --folds`
+        #[arg(
+            long,
+            conflicts_with = "resume",
+            value_parser = RangedU64ValueParser::<usize>::new().range(2..)
+        )]
+        folds: Option<usize>,
         #[arg(short, long, default_value = "32")]
         num_epochs: usize,
         #[arg(short, long, default_value = "1")]
         batch_size: usize,
+        /// Accumulate the gradients of several batches before each optimizer step,
+        /// simulating a batch size of `batch_size * grad_accumulation`.
+        /// The gradients are summed, consider lowering the learning rate with SGD
+        #[arg(long, default_value = "1")]
+        grad_accumulation: usize,
         /// Number of workers for data loading
         #[arg(short = 'w', long, default_value = "1")]
         num_workers: usize,
-        /// Learning rate for the optimizer, decreasing to 1/10 of the given value
+        /// Learning rate for the optimizer, scheduled by `--schedule`
         /// Preset value is suitable for finetuning on `--download-pretrained` enabled
         #[cfg_attr(feature = "f16", arg(short, long, default_value = "1.0e-6"))]
         #[cfg_attr(not(feature = "f16"), arg(short, long, default_value = "1.0e-4"))]
@@ -51,7 +96,8 @@ enum SubCmd {
         download_pretrained: bool,
         #[cfg(not(all(feature = "tch", target_os = "macos")))]
         /// CUDA device to use, -1 for CPU
-        #[arg(short, long, default_value = "0")]
+        #[cfg_attr(feature = "ndarray", arg(short, long, default_value = "-1"))]
+        #[cfg_attr(not(feature = "ndarray"), arg(short, long, default_value = "0"))]
         devices: Vec<isize>,
         /// Random seed for reproducibility
         #[arg(short, long, default_value = "42")]
@@ -59,12 +105,103 @@ enum SubCmd {
         /// Confidence threshold for computing HammingScore
         #[arg(short, long = "threshold", default_value = "0.5")]
         confidence_threshold: f32,
+        /// Optimizer
+        #[arg(long, default_value = "adam")]
+        optimizer: OptimizerType,
+        /// Weight decay, 0 to disable. Decoupled for AdamW, L2 penalty for the others
+        #[arg(long, default_value = "0.0")]
+        weight_decay: f32,
+        /// Momentum of SGD, 0 to disable
+        #[arg(long, default_value = "0.9")]
+        momentum: f64,
+        /// Use Nesterov momentum for SGD
+        #[arg(long)]
+        nesterov: bool,
+        /// Learning rate schedule
+        #[arg(long, default_value = "linear")]
+        schedule: ScheduleType,
+        /// Epochs of linear warmup for the cosine schedule
+        #[arg(long, default_value = "1")]
+        warmup_epochs: usize,
+        /// Epochs between decays for the step schedule
+        #[arg(long, default_value = "10")]
+        step_epochs: usize,
+        /// Decay factor for the step schedule
+        #[arg(long, default_value = "0.1")]
+        step_gamma: f64,
+        /// Epochs training only the last layer with the backbone frozen in inference mode,
+        /// useful for fine-tuning on tiny datasets with `--download-pretrained`
+        #[arg(long, default_value = "0")]
+        freeze_epochs: usize,
+        /// Learning rate of the backbone relative to the last layer after unfreezing
+        #[arg(long, default_value = "1.0")]
+        backbone_lr_factor: f64,
+        /// Decay of the exponential moving average of the weights, 0 to disable
+        #[arg(long, default_value = "0")]
+        ema_decay: f64,
+        /// Metric on the validation set choosing `best_model.mpk` among the raw and EMA models
+        /// each epoch
+        #[arg(long, default_value = "hamming")]
+        best_metric: BestMetric,
+        /// Loss function, use focal or asymmetric loss if rare tags are swamped by frequent ones
+        #[arg(long, default_value = "bce")]
+        loss: LossType,
+        /// Label smoothing, 0 to disable
+        #[arg(long, default_value = "0.1")]
+        smoothing: f32,
+        /// Focusing parameter of the focal loss
+        #[arg(long, default_value = "2.0")]
+        focal_gamma: f32,
+        /// Weight of the positives of the focal loss in [0, 1], unweighted if not given
+        #[arg(long)]
+        focal_alpha: Option<f32>,
+        /// Focusing parameter of the asymmetric loss for the positives
+        #[arg(long, default_value = "0.0")]
+        asl_gamma_pos: f32,
+        /// Focusing parameter of the asymmetric loss for the negatives
+        #[arg(long, default_value = "4.0")]
+        asl_gamma_neg: f32,
+        /// Possibility margin of the asymmetric loss, easier negatives are discarded
+        #[arg(long, default_value = "0.05")]
+        asl_clip: f32,
+        /// Path to the json file configuring data augmentation, see `AugmentConfig`.
+        /// Missing fields use the defaults: flip, rotation and brightness only
+        #[arg(long)]
+        augment: Option<PathBuf>,
+        /// Directory of the preprocessed image cache, filled on the first epoch or by
+        /// `cache build`. Not used for the training set if the augmentation crops
+        #[arg(long)]
+        cache_dir: Option<PathBuf>,
     },
-    /// Predict using a ResNet model checkpoint
+    /// Run trainings sequentially over a grid or random search, ranking them in a leaderboard
+    Sweep {
+        /// Path to the sweep spec json file, with the base training config and the values
+        /// to search by field
+        spec: PathBuf,
+        /// Directory to save the trials in numbered subdirectories and the leaderboard
+        /// (The directory will be recreated if it exists)
+        #[arg(short, long, default_value = "burnxp_sweep")]
+        artifact_dir: PathBuf,
+        #[cfg(not(all(feature = "tch", target_os = "macos")))]
+        /// CUDA device to use, -1 for CPU
+        #[cfg_attr(feature = "ndarray", arg(short, long, default_value = "-1"))]
+        #[cfg_attr(not(feature = "ndarray"), arg(short, long, default_value = "0"))]
+        devices: Vec<isize>,
+    },
+    /// Manage the preprocessed image cache
+    Cache {
+        #[command(subcommand)]
+        cmd: CacheCmd,
+    },
+    /// Predict using a model checkpoint
     Predict {
-        /// Model type
-        #[arg(short, long, default_value=ResNetType::Layer101)]
-        model: ResNetType,
+        /// Model type, overriding the one saved in `model_config.json` next to the checkpoint
+        #[arg(short, long)]
+        model: Option<ModelType>,
+        /// Input size, overriding the one saved in `model_config.json` next to the checkpoint.
+        /// Required if the checkpoint has no `model_config.json`
+        #[arg(long)]
+        size: Option<usize>,
         /// Path to the model checkpoint
         #[arg(short, long)]
         checkpoint: PathBuf,
@@ -83,13 +220,123 @@ enum SubCmd {
         /// only tags with possibility greater than (1 - threshold) will be output
         #[arg(long = "threshold", default_value = "0.5")]
         confidence_threshold: f32,
+        /// Path to the per-tag thresholds produced by `calibrate`, overriding `--threshold`
+        #[arg(long)]
+        thresholds: Option<PathBuf>,
         #[cfg(not(all(feature = "tch", target_os = "macos")))]
         /// CUDA device to use, -1 for CPU
-        #[arg(short, long, default_value = "0")]
+        #[cfg_attr(feature = "ndarray", arg(short, long, default_value = "-1"))]
+        #[cfg_attr(not(feature = "ndarray"), arg(short, long, default_value = "0"))]
         devices: Vec<isize>,
         /// Root of images directory
         input: PathBuf,
     },
+    /// Calibrate per-tag decision thresholds on the validation set
+    Calibrate {
+        /// Model type, overriding the one saved in `model_config.json` next to the checkpoint
+        #[arg(short, long)]
+        model: Option<ModelType>,
+        /// Input size, overriding the one saved in `model_config.json` next to the checkpoint.
+        /// Required if the checkpoint has no `model_config.json`
+        #[arg(long)]
+        size: Option<usize>,
+        /// Path to the model checkpoint
+        #[arg(short, long)]
+        checkpoint: PathBuf,
+        /// Path to the validation set json file produced by the tagger divide
+        #[arg(short, long, default_value = "valid.json")]
+        valid_set: PathBuf,
+        /// Path to save the thresholds
+        #[arg(short, long, default_value = "thresholds.json")]
+        output: PathBuf,
+        /// Choose the lowest threshold reaching this precision instead of maximizing F1
+        #[arg(short, long)]
+        precision: Option<f32>,
+        #[arg(short, long, default_value = "1")]
+        batch_size: usize,
+        /// Number of workers for data loading
+        #[arg(short = 'w', long, default_value = "1")]
+        num_workers: usize,
+        #[cfg(not(all(feature = "tch", target_os = "macos")))]
+        /// CUDA device to use, -1 for CPU
+        #[cfg_attr(feature = "ndarray", arg(short, long, default_value = "-1"))]
+        #[cfg_attr(not(feature = "ndarray"), arg(short, long, default_value = "0"))]
+        devices: Vec<isize>,
+    },
+    /// Export image embeddings (backbone features) using a model checkpoint
+    Embed {
+        /// Model type, overriding the one saved in `model_config.json` next to the checkpoint
+        #[arg(short, long)]
+        model: Option<ModelType>,
+        /// Input size, overriding the one saved in `model_config.json` next to the checkpoint.
+        /// Required if the checkpoint has no `model_config.json`
+        #[arg(long)]
+        size: Option<usize>,
+        /// Path to the model checkpoint
+        #[arg(short, long)]
+        checkpoint: PathBuf,
+        /// Path to save the embeddings, rows of little-endian f32
+        #[arg(short, long, default_value = "embeddings.bin")]
+        output: PathBuf,
+        /// Path to save the index of the image path of each row
+        #[arg(short, long, default_value = "embeddings.json")]
+        index: PathBuf,
+        #[arg(short, long, default_value = "1")]
+        batch_size: usize,
+        /// Number of workers for data loading
+        #[arg(short = 'w', long, default_value = "1")]
+        num_workers: usize,
+        /// Path to the file which contains the tags' weights
+        #[arg(short, long, default_value = "tags.json")]
+        tags: PathBuf,
+        #[cfg(not(all(feature = "tch", target_os = "macos")))]
+        /// CUDA device to use, -1 for CPU
+        #[cfg_attr(feature = "ndarray", arg(short, long, default_value = "-1"))]
+        #[cfg_attr(not(feature = "ndarray"), arg(short, long, default_value = "0"))]
+        devices: Vec<isize>,
+        /// Root of images directory
+        input: PathBuf,
+    },
+    /// Serve a localhost HTTP API for prediction, loading the model only once
+    Serve {
+        /// Model type, overriding the one saved in `model_config.json` next to the checkpoint
+        #[arg(short, long)]
+        model: Option<ModelType>,
+        /// Input size, overriding the one saved in `model_config.json` next to the checkpoint.
+        /// Required if the checkpoint has no `model_config.json`
+        #[arg(long)]
+        size: Option<usize>,
+        /// Path to the model checkpoint
+        #[arg(short, long)]
+        checkpoint: PathBuf,
+        /// Address to listen on
+        #[arg(short, long, default_value = "127.0.0.1:8080")]
+        address: String,
+        /// Max number of concurrent requests sharing one forward pass
+        #[arg(short, long, default_value = "8")]
+        batch_size: usize,
+        /// Milliseconds to wait for more requests to fill a batch
+        #[arg(long, default_value = "10")]
+        batch_wait: u64,
+        /// Number of threads handling requests
+        #[arg(short = 'w', long, default_value = "4")]
+        num_workers: usize,
+        /// Path to the file which contains the tags' weights
+        #[arg(short, long, default_value = "tags.json")]
+        tags: PathBuf,
+        /// Confidence threshold for the prediction,
+        /// only tags with possibility greater than (1 - threshold) will be output
+        #[arg(long = "threshold", default_value = "0.5")]
+        confidence_threshold: f32,
+        /// Path to the per-tag thresholds produced by `calibrate`, overriding `--threshold`
+        #[arg(long)]
+        thresholds: Option<PathBuf>,
+        #[cfg(not(all(feature = "tch", target_os = "macos")))]
+        /// CUDA device to use, -1 for CPU
+        #[cfg_attr(feature = "ndarray", arg(short, long, default_value = "-1"))]
+        #[cfg_attr(not(feature = "ndarray"), arg(short, long, default_value = "0"))]
+        devices: Vec<isize>,
+    },
     /// generate auto completion script
     GenCompletion {
         /// shell name
@@ -97,6 +344,29 @@ enum SubCmd {
     },
 }
 
+#[derive(Debug, Subcommand)]
+enum CacheCmd {
+    /// Preprocess the images of the data sets into the cache
+    Build {
+        /// Paths to the data set json files produced by the tagger divide
+        #[arg(default_values = ["train.json", "valid.json"])]
+        sets: Vec<PathBuf>,
+        /// Input size of the model, images are resized to size x size
+        #[arg(long, default_value = "720")]
+        size: usize,
+        #[arg(short, long, default_value = "burnxp_cache")]
+        cache_dir: PathBuf,
+        /// Number of threads processing the images
+        #[arg(short = 'w', long, default_value = "8")]
+        num_workers: usize,
+    },
+    /// Remove the cache
+    Clean {
+        #[arg(short, long, default_value = "burnxp_cache")]
+        cache_dir: PathBuf,
+    },
+}
+
 #[cfg(feature = "f16")]
 type Precision = burn::tensor::f16;
 #[cfg(not(feature = "f16"))]
@@ -106,6 +376,8 @@ type Precision = f32;
 type MyBackend = burn::backend::LibTorch<Precision, i8>;
 #[cfg(feature = "candle")]
 type MyBackend = burn::backend::Candle<Precision, u8>;
+#[cfg(feature = "ndarray")]
+type MyBackend = burn::backend::NdArray<Precision, i64>;
 
 type MyAutodiffBackend = Autodiff<MyBackend>;
 
@@ -114,11 +386,18 @@ pub fn run(name: &str) {
     match args.subcmd {
         SubCmd::Train {
             model,
+            size,
             train_set,
             valid_set,
+            tags,
+            train_ratio,
+            valid_ratio,
             artifact_dir,
+            resume,
+            folds,
             num_epochs,
             batch_size,
+            grad_accumulation,
             num_workers,
             learning_rate,
             early_stopping,
@@ -128,34 +407,128 @@ pub fn run(name: &str) {
             devices,
             seed,
             confidence_threshold,
+            optimizer,
+            weight_decay,
+            momentum,
+            nesterov,
+            schedule,
+            warmup_epochs,
+            step_epochs,
+            step_gamma,
+            freeze_epochs,
+            backbone_lr_factor,
+            ema_decay,
+            best_metric,
+            loss,
+            smoothing,
+            focal_gamma,
+            focal_alpha,
+            asl_gamma_pos,
+            asl_gamma_neg,
+            asl_clip,
+            augment,
+            cache_dir,
+        } => {
+            let devices = get_devices(
+                #[cfg(not(all(feature = "tch", target_os = "macos")))]
+                devices,
+            );
+
+            if resume {
+                resume_train::<MyAutodiffBackend>(artifact_dir, devices);
+                return;
+            }
+            let size = size.unwrap_or_else(|| model.default_size());
+            let config = TrainingConfig::new(
+                ModelConfig::new(model).with_size(size).with_loss(
+                    LossConfig::new()
+                        .with_loss_type(loss)
+                        .with_smoothing(smoothing)
+                        .with_focal_gamma(focal_gamma)
+                        .with_focal_alpha(focal_alpha)
+                        .with_asl_gamma_pos(asl_gamma_pos)
+                        .with_asl_gamma_neg(asl_gamma_neg)
+                        .with_asl_clip(asl_clip),
+                ),
+                OptimizerConfig::new()
+                    .with_optimizer_type(optimizer)
+                    .with_weight_decay(weight_decay)
+                    .with_momentum(momentum)
+                    .with_nesterov(nesterov),
+                train_set,
+                valid_set,
+            )
+            .with_num_epochs(num_epochs)
+            .with_batch_size(batch_size)
+            .with_grad_accumulation(grad_accumulation)
+            .with_num_workers(num_workers)
+            .with_learning_rate(learning_rate)
+            .with_pretrained(pretrained)
+            .with_download_pretrained(download_pretrained)
+            .with_early_stopping(early_stopping)
+            .with_seed(seed)
+            .with_confidence_threshold(confidence_threshold)
+            .with_schedule(
+                ScheduleConfig::new()
+                    .with_schedule_type(schedule)
+                    .with_warmup_epochs(warmup_epochs)
+                    .with_step_epochs(step_epochs)
+                    .with_step_gamma(step_gamma),
+            )
+            .with_freeze_epochs(freeze_epochs)
+            .with_backbone_lr_factor(backbone_lr_factor)
+            .with_ema_decay(ema_decay)
+            .with_best_metric(best_metric)
+            .with_augment(augment.map_or_else(AugmentConfig::new, |path| {
+                let augment =
+                    AugmentConfig::load(path).expect("Augmentation config should be legal");
+                augment
+                    .validate()
+                    .expect("Augmentation config should be valid");
+                augment
+            }))
+            .with_cache_dir(cache_dir)
+            .with_divide(tags.map(|tags| {
+                DivideConfig::new(tags)
+                    .with_train(train_ratio)
+                    .with_valid(valid_ratio)
+            }));
+            match folds {
+                Some(folds) => {
+                    train_folds::<MyAutodiffBackend>(artifact_dir, config, folds, devices)
+                }
+                None => train::<MyAutodiffBackend>(artifact_dir, config, devices),
+            }
+        }
+        SubCmd::Sweep {
+            spec,
+            artifact_dir,
+            #[cfg(not(all(feature = "tch", target_os = "macos")))]
+            devices,
         } => {
             let devices = get_devices(
                 #[cfg(not(all(feature = "tch", target_os = "macos")))]
                 devices,
             );
 
-            train::<MyAutodiffBackend>(
+            sweep::<MyAutodiffBackend>(
                 artifact_dir,
-                TrainingConfig::new(
-                    ModelConfig::new(model),
-                    AdamConfig::new(),
-                    train_set,
-                    valid_set,
-                )
-                .with_num_epochs(num_epochs)
-                .with_batch_size(batch_size)
-                .with_num_workers(num_workers)
-                .with_learning_rate(learning_rate)
-                .with_pretrained(pretrained)
-                .with_download_pretrained(download_pretrained)
-                .with_early_stopping(early_stopping)
-                .with_seed(seed)
-                .with_confidence_threshold(confidence_threshold),
+                SweepConfig::load(spec).expect("Sweep spec should be valid"),
                 devices,
             );
         }
+        SubCmd::Cache { cmd } => match cmd {
+            CacheCmd::Build {
+                sets,
+                size,
+                cache_dir,
+                num_workers,
+            } => build_cache(cache_dir, sets, size, num_workers),
+            CacheCmd::Clean { cache_dir } => clean_cache(cache_dir),
+        },
         SubCmd::Predict {
             model,
+            size,
             checkpoint,
             input,
             output,
@@ -163,6 +536,7 @@ pub fn run(name: &str) {
             num_workers,
             tags,
             confidence_threshold,
+            thresholds,
             #[cfg(not(all(feature = "tch", target_os = "macos")))]
             devices,
         } => {
@@ -172,10 +546,100 @@ pub fn run(name: &str) {
             );
 
             predict::<MyBackend>(
-                PredictConfig::new(model, checkpoint, input, output, tags)
+                PredictConfig::new(checkpoint, input, output, tags)
+                    .with_model(model)
+                    .with_size(size)
                     .with_batch_size(batch_size)
                     .with_num_workers(num_workers)
-                    .with_confidence_threshold(confidence_threshold),
+                    .with_confidence_threshold(confidence_threshold)
+                    .with_thresholds(thresholds),
+                devices,
+            )
+            .expect("Prediction should succeed")
+        }
+        SubCmd::Calibrate {
+            model,
+            size,
+            checkpoint,
+            valid_set,
+            output,
+            precision,
+            batch_size,
+            num_workers,
+            #[cfg(not(all(feature = "tch", target_os = "macos")))]
+            devices,
+        } => {
+            let devices = get_devices(
+                #[cfg(not(all(feature = "tch", target_os = "macos")))]
+                devices,
+            );
+
+            calibrate::<MyBackend>(
+                CalibrateConfig::new(checkpoint, valid_set, output)
+                    .with_model(model)
+                    .with_size(size)
+                    .with_target_precision(precision)
+                    .with_batch_size(batch_size)
+                    .with_num_workers(num_workers),
+                devices,
+            )
+        }
+        SubCmd::Embed {
+            model,
+            size,
+            checkpoint,
+            output,
+            index,
+            batch_size,
+            num_workers,
+            tags,
+            #[cfg(not(all(feature = "tch", target_os = "macos")))]
+            devices,
+            input,
+        } => {
+            let devices = get_devices(
+                #[cfg(not(all(feature = "tch", target_os = "macos")))]
+                devices,
+            );
+
+            embed::<MyBackend>(
+                EmbedConfig::new(checkpoint, input, output, index, tags)
+                    .with_model(model)
+                    .with_size(size)
+                    .with_batch_size(batch_size)
+                    .with_num_workers(num_workers),
+                devices,
+            )
+        }
+        SubCmd::Serve {
+            model,
+            size,
+            checkpoint,
+            address,
+            batch_size,
+            batch_wait,
+            num_workers,
+            tags,
+            confidence_threshold,
+            thresholds,
+            #[cfg(not(all(feature = "tch", target_os = "macos")))]
+            devices,
+        } => {
+            let devices = get_devices(
+                #[cfg(not(all(feature = "tch", target_os = "macos")))]
+                devices,
+            );
+
+            serve::<MyBackend>(
+                ServeConfig::new(checkpoint, tags)
+                    .with_model(model)
+                    .with_size(size)
+                    .with_address(address)
+                    .with_batch_size(batch_size)
+                    .with_batch_wait(batch_wait)
+                    .with_num_workers(num_workers)
+                    .with_confidence_threshold(confidence_threshold)
+                    .with_thresholds(thresholds),
                 devices,
             )
         }
@@ -243,3 +707,12 @@ fn get_devices(devices: Vec<isize>) -> Vec<burn::backend::candle::CandleDevice>
     };
     devices
 }
+
+#[cfg(feature = "ndarray")]
+fn get_devices(devices: Vec<isize>) -> Vec<burn::backend::ndarray::NdArrayDevice> {
+    assert!(
+        devices.iter().all(|&i| i == -1),
+        "only CPU (-1) is supported by the ndarray backend"
+    );
+    vec![burn::backend::ndarray::NdArrayDevice::Cpu]
+}
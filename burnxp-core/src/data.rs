@@ -1,50 +1,155 @@
+use crate::{augment::AugmentConfig, cache::ImageCache};
 use anyhow::Result;
 use burn::{
     data::dataloader::{batcher::Batcher, Dataset},
     prelude::*,
 };
-use image::{
-    imageops::colorops::brighten_in_place, imageops::FilterType, DynamicImage, ImageBuffer, Rgb,
+use image::{imageops::FilterType, DynamicImage, RgbImage};
+use rand::{seq::SliceRandom, thread_rng};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap},
+    fs::File,
+    path::Path,
+    sync::{Arc, Mutex},
 };
-use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
-use mime_guess::MimeGuess;
-use rand::{seq::SliceRandom, thread_rng, Rng};
-use std::{collections::HashMap, path::Path};
-use std::{f32::consts::PI, path::PathBuf};
-use tagger::{BitFlags, DataSetDesc};
+use tagger::{is_image, open_image, BitFlags, DataSetDesc};
 
-const SIZE: usize = 720;
-const HALF: i64 = (SIZE / 2) as i64;
+/// Attempts to replace an undecodable image of the training set with another of the same tags
+const MAX_RETRIES: usize = 10;
 
 #[derive(Debug, Clone)]
 pub(crate) struct ImageData<B: Backend> {
-    data: Tensor<B, 1>,
+    /// `None` if the image failed to decode, dropped by the batcher
+    data: Option<Tensor<B, 1>>,
     #[cfg(feature = "tch")]
     tags: Tensor<B, 1, Int>,
     #[cfg(feature = "candle")]
     tags: Tensor<B, 1, Int>,
+    #[cfg(feature = "ndarray")]
+    tags: Tensor<B, 1, Int>,
     path: PathBuf,
 }
 
+impl<B: Backend> ImageData<B> {
+    /// An image without tags, to be predicted
+    pub(crate) fn from_image(img: DynamicImage, path: PathBuf, size: usize) -> Option<Self> {
+        Some(Self {
+            data: Some(normalize(&resize_image(img, size)?)),
+            tags: Tensor::empty([0], &B::Device::default()),
+            path,
+        })
+    }
+}
+
 pub(crate) struct ImageDataSet {
     num_classes: usize,
     pub len: usize,
+    /// input size of the model
+    size: usize,
+    /// only the training set is augmented
+    augment: Option<AugmentConfig>,
     up_sample: Option<Vec<(usize, BitFlags)>>,
+    /// only for the up-sampled training set
     binary_encodings: HashMap<BitFlags, Vec<PathBuf>>,
+    /// the images of the validation or prediction set sorted by path, indexed in O(1)
+    index: Vec<(PathBuf, BitFlags)>,
+    cache: Option<ImageCache>,
+    skipped: Arc<SkipReport>,
+}
+
+/// An image excluded from a data set
+#[derive(Debug, Serialize)]
+struct Skipped<'a> {
+    path: &'a Path,
+    reason: &'a str,
+}
+
+/// Images excluded by the pre-flight check or failing to decode while running,
+/// reported on stderr as they come and in the file if any on `save`
+#[derive(Debug)]
+pub(crate) struct SkipReport {
+    file: Option<PathBuf>,
+    skipped: Mutex<BTreeMap<PathBuf, String>>,
+}
+
+impl SkipReport {
+    pub(crate) fn new(file: Option<PathBuf>) -> Arc<Self> {
+        Arc::new(Self {
+            file,
+            skipped: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    fn skip(&self, path: &Path, reason: impl ToString) {
+        let mut skipped = self
+            .skipped
+            .lock()
+            .expect("Skip report should not be poisoned");
+        if let Entry::Vacant(entry) = skipped.entry(path.to_path_buf()) {
+            let reason = reason.to_string();
+            eprintln!("Skipped image {}: {}", path.display(), reason);
+            entry.insert(reason);
+        }
+    }
+
+    /// Write the skipped images sorted by path to the file if any
+    pub(crate) fn save(&self) {
+        let Some(ref file) = self.file else {
+            return;
+        };
+        let skipped = self
+            .skipped
+            .lock()
+            .expect("Skip report should not be poisoned");
+        let skipped = skipped
+            .iter()
+            .map(|(path, reason)| Skipped { path, reason })
+            .collect::<Vec<_>>();
+        let res = File::create(file)
+            .map_err(anyhow::Error::from)
+            .and_then(|f| Ok(serde_json::to_writer_pretty(f, &skipped)?));
+        if let Err(e) = res {
+            eprintln!("Failed to write {}: {}", file.display(), e);
+        }
+    }
+
+    /// Whether the image passes the pre-flight check, which only reads its header
+    fn check(&self, path: &Path) -> bool {
+        let res = (|| -> Result<()> {
+            let (width, height) = image::ImageReader::open(path.canonicalize()?)?
+                .with_guessed_format()?
+                .into_dimensions()?;
+            anyhow::ensure!(width > 0 && height > 0, "empty image");
+            Ok(())
+        })();
+        if let Err(ref e) = res {
+            self.skip(path, e);
+        }
+        res.is_ok()
+    }
 }
 
 impl ImageDataSet {
-    pub(crate) fn train(desc: DataSetDesc) -> Result<Self> {
-        for path in desc.binary_encodings.iter().flat_map(|(_, v)| v) {
-            assert!(
-                path.canonicalize().is_ok(),
-                "expected {} to exist",
-                path.display()
-            );
+    pub(crate) fn train(
+        mut desc: DataSetDesc,
+        size: usize,
+        augment: AugmentConfig,
+        skipped: Arc<SkipReport>,
+    ) -> Result<Self> {
+        for paths in desc.binary_encodings.values_mut() {
+            paths.retain(|path| skipped.check(path));
         }
+        desc.binary_encodings.retain(|_, paths| !paths.is_empty());
+        // the tags left without any image are not sampled
+        desc.up_sample
+            .retain(|flags, _| desc.binary_encodings.contains_key(flags));
         Ok(Self {
             num_classes: desc.num_classes,
             len: desc.up_sample.values().sum(),
+            size,
+            augment: Some(augment),
             up_sample: Some(desc.up_sample.into_iter().fold(vec![], |mut acc, (k, v)| {
                 match acc.last() {
                     None => acc.push((v, k)),
@@ -53,41 +158,84 @@ impl ImageDataSet {
                 acc
             })),
             binary_encodings: desc.binary_encodings,
+            index: vec![],
+            cache: None,
+            skipped,
         })
     }
 
-    pub(crate) fn valid(desc: DataSetDesc) -> Result<Self> {
-        for path in desc.binary_encodings.iter().flat_map(|(_, v)| v) {
-            assert!(
-                path.canonicalize().is_ok(),
-                "expected {} to exist",
-                path.display()
-            );
-        }
+    pub(crate) fn valid(desc: DataSetDesc, size: usize, skipped: Arc<SkipReport>) -> Result<Self> {
+        let mut index = desc
+            .binary_encodings
+            .into_iter()
+            .flat_map(|(flags, paths)| paths.into_iter().map(move |path| (path, flags)))
+            .collect::<Vec<_>>();
+        index.sort_by(|a, b| a.0.cmp(&b.0));
+        index.retain(|(path, _)| skipped.check(path));
         Ok(Self {
             num_classes: desc.num_classes,
-            len: desc.binary_encodings.values().map(|v| v.len()).sum(),
+            len: index.len(),
+            size,
+            augment: None,
             up_sample: None,
-            binary_encodings: desc.binary_encodings,
+            binary_encodings: HashMap::new(),
+            index,
+            cache: None,
+            skipped,
         })
     }
 
-    pub(crate) fn predict(path: PathBuf) -> Result<Self> {
-        let inner = walkdir::WalkDir::new(path)
+    pub(crate) fn predict(path: PathBuf, size: usize, skipped: Arc<SkipReport>) -> Result<Self> {
+        let mut index = walkdir::WalkDir::new(path)
+            .sort_by_file_name()
             .into_iter()
             .filter_map(|res| res.ok())
-            .filter_map(|e| match MimeGuess::from_path(e.path()).first() {
-                Some(mime) if mime.type_() == "image" => Some(e.into_path()),
-                _ => None,
-            })
+            .filter(|e| is_image(e.path()))
+            .map(|e| (e.into_path(), 0.into()))
             .collect::<Vec<_>>();
+        index.retain(|(path, _)| skipped.check(path));
         Ok(Self {
             num_classes: 0,
-            len: inner.len(),
+            len: index.len(),
+            size,
+            augment: None,
             up_sample: None,
-            binary_encodings: [(0.into(), inner)].into(),
+            binary_encodings: HashMap::new(),
+            index,
+            cache: None,
+            skipped,
         })
     }
+
+    /// Read the resized images from the cache, filling it on misses
+    pub(crate) fn with_cache(mut self, cache: Option<ImageCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Load the image, reporting it if it fails to decode
+    fn load<B: Backend>(&self, path: &Path, flags: &BitFlags) -> ImageData<B> {
+        let cache = self.cache.as_ref();
+        let data = match self.augment {
+            Some(ref augment) => open_image_proc(path, self.size, augment, cache),
+            None => open_image_normalize(path, self.size, cache),
+        };
+        if data.is_none() {
+            self.skipped.skip(path, "failed to decode");
+        }
+        ImageData {
+            data,
+            tags: {
+                #[cfg(any(feature = "tch", feature = "ndarray"))]
+                let mut t: Vec<i8> = Vec::from(*flags);
+                #[cfg(feature = "candle")]
+                let mut t: Vec<u8> = Vec::from(*flags);
+                t.truncate(self.num_classes);
+                Tensor::from_ints(&t[..], &B::Device::default())
+            },
+            path: path.to_path_buf(),
+        }
+    }
 }
 
 impl<B: Backend> Dataset<ImageData<B>> for ImageDataSet {
@@ -99,42 +247,23 @@ impl<B: Backend> Dataset<ImageData<B>> for ImageDataSet {
                     .find(|(v, _)| index < *v)
                     .map(|(_, f)| f)
                     .expect("Index out of bounds");
-                let path = self.binary_encodings[flags]
-                    .choose(&mut thread_rng())
-                    .expect("Index out of bounds");
-                Some(ImageData {
-                    data: open_image_proc(path).unwrap_or_else(|| {
-                        panic!("Failed to load and process image {}", path.display())
-                    }),
-                    tags: {
-                        #[cfg(feature = "tch")]
-                        let mut t: Vec<i8> = Vec::from(*flags);
-                        #[cfg(feature = "candle")]
-                        let mut t: Vec<u8> = Vec::from(*flags);
-                        t.truncate(self.num_classes);
-                        Tensor::from_ints(&t[..], &B::Device::default())
-                    },
-                    path: path.clone(),
-                })
+                let paths = &self.binary_encodings[flags];
+                let mut rng = thread_rng();
+                let mut pick = move || paths.choose(&mut rng).expect("Index out of bounds");
+                // another image of the same tags replaces an undecodable one
+                let mut item = self.load(pick(), flags);
+                for _ in 1..MAX_RETRIES {
+                    if item.data.is_some() {
+                        break;
+                    }
+                    item = self.load(pick(), flags);
+                }
+                Some(item)
+            }
+            None => {
+                let (path, flags) = self.index.get(index)?;
+                Some(self.load(path, flags))
             }
-            None => self
-                .binary_encodings
-                .iter()
-                .flat_map(|(k, v)| v.iter().map(move |path| (path, k)))
-                .nth(index)
-                .map(|(path, flags)| ImageData {
-                    data: open_image_normalize(path)
-                        .unwrap_or_else(|| panic!("Failed to load image {}", path.display())),
-                    tags: {
-                        #[cfg(feature = "tch")]
-                        let mut t: Vec<i8> = Vec::from(*flags);
-                        #[cfg(feature = "candle")]
-                        let mut t: Vec<u8> = Vec::from(*flags);
-                        t.truncate(self.num_classes);
-                        Tensor::from_ints(&t[..], &B::Device::default())
-                    },
-                    path: path.clone(),
-                }),
         }
     }
 
@@ -144,74 +273,137 @@ impl<B: Backend> Dataset<ImageData<B>> for ImageDataSet {
 }
 
 #[derive(Clone)]
-pub(crate) struct ImageBatcher {}
+pub(crate) struct ImageBatcher {
+    /// input size of the model
+    size: usize,
+    /// Mixup and CutMix, only for the training set
+    mix: Option<AugmentConfig>,
+}
 
 #[derive(Debug, Clone)]
 pub(crate) struct ImageBatch<B: Backend> {
     pub datas: Tensor<B, 4>,
+    /// hard targets for the metrics, the dominant image's tags if mixed
     pub targets: Tensor<B, 2, Int>,
+    /// targets for the loss, blended if mixed
+    pub soft_targets: Tensor<B, 2>,
     pub paths: Vec<PathBuf>,
 }
 
 impl ImageBatcher {
-    pub(crate) fn new() -> Self {
-        Self {}
+    pub(crate) fn new(size: usize) -> Self {
+        Self { size, mix: None }
+    }
+
+    pub(crate) fn with_mix(mut self, augment: AugmentConfig) -> Self {
+        self.mix = Some(augment);
+        self
     }
 }
 
 impl<B: Backend> Batcher<ImageData<B>, ImageBatch<B>> for ImageBatcher {
     fn batch(&self, items: Vec<ImageData<B>>) -> ImageBatch<B> {
-        let (paths, datas, targets) = items.into_iter().fold(
+        let num_classes = items.first().map_or(0, |item| item.tags.dims()[0]);
+        // the images failing to decode are dropped
+        let (paths, datas, targets) = items.into_iter().filter(|item| item.data.is_some()).fold(
             (vec![], vec![], vec![]),
             |(mut paths, mut datas, mut targets), item| {
                 paths.push(item.path);
-                datas.push(item.data.reshape([1, SIZE, SIZE, 3]).permute([0, 3, 1, 2]));
+                datas.push(
+                    item.data
+                        .expect("Undecoded images should be filtered")
+                        .reshape([1, self.size, self.size, 3])
+                        .permute([0, 3, 1, 2]),
+                );
                 targets.push(item.tags.reshape([1, -1]));
                 (paths, datas, targets)
             },
         );
 
+        if paths.is_empty() {
+            let device = B::Device::default();
+            let targets = Tensor::<B, 2, Int>::empty([0, num_classes], &device);
+            return ImageBatch {
+                datas: Tensor::empty([0, 3, self.size, self.size], &device),
+                soft_targets: targets.clone().float(),
+                targets,
+                paths,
+            };
+        }
+
         let datas = Tensor::cat(datas, 0);
         let targets = Tensor::cat(targets, 0);
 
-        ImageBatch {
-            datas,
-            targets,
-            paths,
+        match self.mix {
+            Some(ref mix) => {
+                let (datas, soft_targets) = mix.mix(datas, targets.float());
+                ImageBatch {
+                    datas,
+                    targets: soft_targets.clone().greater_equal_elem(0.5).int(),
+                    soft_targets,
+                    paths,
+                }
+            }
+            None => ImageBatch {
+                datas,
+                soft_targets: targets.clone().float(),
+                targets,
+                paths,
+            },
         }
     }
 }
 
-fn open_image_proc<B: Backend>(path: impl AsRef<Path>) -> Option<Tensor<B, 1>> {
-    let size = SIZE as u32;
-    let mut img = open_image_resize(path)?;
+fn open_image_proc<B: Backend>(
+    path: impl AsRef<Path>,
+    size: usize,
+    augment: &AugmentConfig,
+    cache: Option<&ImageCache>,
+) -> Option<Tensor<B, 1>> {
     let mut rng = thread_rng();
-    if rng.gen_bool(0.5) {
-        img = img.fliph();
-    }
-    let theta = rng.gen_range(-1. / 6. ..1. / 6.);
-    let buffer = img.to_rgb8().into_raw();
-    let buffer = ImageBuffer::<Rgb<u8>, Vec<u8>>::from_vec(size, size, buffer)?;
-    let mut buffer =
-        rotate_about_center(&buffer, theta * PI, Interpolation::Nearest, Rgb([0, 0, 0]));
-    brighten_in_place(&mut buffer, rng.gen_range(-32..32));
+    let img = match cache.filter(|_| !augment.crops()) {
+        Some(cache) => DynamicImage::ImageRgb8(RgbImage::from_raw(
+            size as u32,
+            size as u32,
+            cache.load(path.as_ref(), size)?.to_vec(),
+        )?),
+        None => {
+            let img = open_image(path.as_ref().canonicalize().ok()?).ok()?;
+            resize_image(augment.crop(img, &mut rng), size)?
+        }
+    };
+    let buffer = augment.apply(img, &mut rng);
     Some(Tensor::from_data(&buffer.into_raw()[..], &B::Device::default()) * 2. / 255. - 1.)
 }
 
-fn open_image_normalize<B: Backend>(path: impl AsRef<Path>) -> Option<Tensor<B, 1>> {
-    Some(
-        Tensor::from_data(
-            &open_image_resize(path)?.to_rgb8().into_raw()[..],
-            &B::Device::default(),
-        ) * 2.
-            / 255.
-            - 1.,
-    )
+fn open_image_normalize<B: Backend>(
+    path: impl AsRef<Path>,
+    size: usize,
+    cache: Option<&ImageCache>,
+) -> Option<Tensor<B, 1>> {
+    match cache {
+        Some(cache) => Some(normalize_raw(&cache.load(path.as_ref(), size)?)),
+        None => Some(normalize(&open_image_resize(path, size)?)),
+    }
+}
+
+fn normalize<B: Backend>(img: &DynamicImage) -> Tensor<B, 1> {
+    normalize_raw(&img.to_rgb8().into_raw())
+}
+
+fn normalize_raw<B: Backend>(buf: &[u8]) -> Tensor<B, 1> {
+    Tensor::from_data(buf, &B::Device::default()) * 2. / 255. - 1.
+}
+
+pub(crate) fn open_image_resize(path: impl AsRef<Path>, size: usize) -> Option<DynamicImage> {
+    let img = open_image(path.as_ref().canonicalize().ok()?).ok()?;
+    resize_image(img, size)
 }
 
-fn open_image_resize(path: impl AsRef<Path>) -> Option<DynamicImage> {
-    let size = SIZE as u32;
-    let img = image::open(path.as_ref().canonicalize().ok()?).ok()?;
+/// Resize the image to fit in size x size, letterboxing it with black
+fn resize_image(img: DynamicImage, size: usize) -> Option<DynamicImage> {
+    let half = (size / 2) as i64;
+    let size = size as u32;
     let mut background = image::RgbImage::new(size, size);
 
     let factor = img.height().max(img.width()) as f64 / size as f64;
@@ -225,8 +417,8 @@ fn open_image_resize(path: impl AsRef<Path>) -> Option<DynamicImage> {
     image::imageops::overlay(
         &mut background,
         &img.to_rgb8(),
-        HALF - (nwidth / 2) as i64,
-        HALF - (nheight / 2) as i64,
+        half - (nwidth / 2) as i64,
+        half - (nheight / 2) as i64,
     );
     Some(DynamicImage::ImageRgb8(background))
 }
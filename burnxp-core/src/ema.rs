@@ -0,0 +1,279 @@
+use crate::{
+    data::{ImageBatcher, ImageDataSet},
+    eval::{EvalReport, Inference},
+    model::Model,
+};
+use burn::{
+    data::dataloader::DataLoaderBuilder,
+    module::{AutodiffModule, ModuleMapper, ModuleVisitor, ParamId},
+    optim::{GradientsParams, Optimizer},
+    prelude::*,
+    record::CompactRecorder,
+    tensor::backend::AutodiffBackend,
+    LearningRate,
+};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    marker::PhantomData,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+pub enum BestMetric {
+    /// HammingScore at the confidence threshold
+    #[default]
+    Hamming,
+    /// Mean average precision over the tags
+    Map,
+}
+
+impl BestMetric {
+    fn score(&self, inference: &Inference, threshold: f32) -> f32 {
+        match self {
+            Self::Hamming => inference.hamming_score(threshold),
+            Self::Map => EvalReport::new(inference, &[], threshold).mean_average_precision,
+        }
+    }
+}
+
+/// How the raw and EMA models are selected at the end of each epoch
+pub(crate) struct Selection {
+    pub artifact_dir: PathBuf,
+    /// the validation set, only to evaluate the EMA model
+    pub dataset: Arc<ImageDataSet>,
+    pub size: usize,
+    pub batch_size: usize,
+    pub num_workers: usize,
+    pub num_classes: usize,
+    pub threshold: f32,
+    pub metric: BestMetric,
+}
+
+/// Saved as `best_model.json` along with `best_model.mpk`
+#[derive(Debug, Serialize, Deserialize)]
+struct BestModel {
+    epoch: usize,
+    ema: bool,
+    metric: BestMetric,
+    score: f32,
+}
+
+/// The EMA weights kept by `Ema`, read by `Selector` between the epochs
+pub(crate) type EmaWeights<B> = Arc<Mutex<Option<Model<B>>>>;
+
+/// Export the best of the raw and EMA models by validation as `best_model.mpk`, called at the
+/// end of each validation pass of the learner
+pub(crate) struct Selector<B: Backend> {
+    selection: Selection,
+    /// none if the EMA is disabled
+    ema: Option<EmaWeights<B>>,
+    /// the epoch and score of the best raw model, copied from its checkpoint once the
+    /// learner has written it after the validation
+    pending: Option<(usize, f32)>,
+    best: f32,
+}
+
+impl<B: Backend> Selector<B> {
+    pub(crate) fn new(selection: Selection, ema: Option<EmaWeights<B>>) -> Self {
+        // the best score so far is kept when resuming
+        let best = File::open(selection.artifact_dir.join("best_model.json"))
+            .ok()
+            .and_then(|f| serde_json::from_reader::<_, BestModel>(f).ok())
+            .filter(|best| best.metric == selection.metric)
+            .map_or(f32::NEG_INFINITY, |best| best.score);
+        Self {
+            selection,
+            ema,
+            pending: None,
+            best,
+        }
+    }
+
+    /// Select among the raw model of the epoch, whose validation is `inference`, and the
+    /// EMA model, which is the only one evaluated here
+    pub(crate) fn select(&mut self, epoch: usize, inference: &Inference) {
+        // the checkpoint of the previous epoch is written by now
+        self.finish();
+        let selection = &self.selection;
+        let score = |inference: &Inference| selection.metric.score(inference, selection.threshold);
+        let raw = score(inference);
+        if raw > self.best {
+            self.best = raw;
+            self.pending = Some((epoch, raw));
+        }
+        let Some(model) = self.ema.as_ref().and_then(|ema| {
+            ema.lock()
+                .expect("EMA weights should not be poisoned")
+                .clone()
+        }) else {
+            return;
+        };
+        let dataloader = DataLoaderBuilder::new(ImageBatcher::new(selection.size))
+            .batch_size(selection.batch_size)
+            .num_workers(selection.num_workers)
+            .build(selection.dataset.clone());
+        let score = score(&Inference::run(&model, dataloader, selection.num_classes));
+        if score > self.best {
+            self.best = score;
+            self.pending = None;
+            model
+                .save_file(
+                    selection.artifact_dir.join("best_model"),
+                    &CompactRecorder::new(),
+                )
+                .expect("Best model should be saved successfully");
+            self.describe(epoch, true, score);
+        }
+    }
+
+    /// Copy the checkpoint of the best raw model pending as `best_model.mpk`
+    pub(crate) fn finish(&mut self) {
+        let Some((epoch, score)) = self.pending.take() else {
+            return;
+        };
+        let artifact_dir = &self.selection.artifact_dir;
+        std::fs::copy(
+            artifact_dir
+                .join("checkpoint")
+                .join(format!("model-{}.mpk", epoch)),
+            artifact_dir.join("best_model.mpk"),
+        )
+        .expect("Best model should be saved successfully");
+        self.describe(epoch, false, score);
+    }
+
+    fn describe(&self, epoch: usize, ema: bool, score: f32) {
+        let best = BestModel {
+            epoch,
+            ema,
+            metric: self.selection.metric,
+            score,
+        };
+        serde_json::to_writer_pretty(
+            File::create(self.selection.artifact_dir.join("best_model.json"))
+                .expect("Best model description should be saved successfully"),
+            &best,
+        )
+        .expect("Best model description should be saved successfully");
+    }
+}
+
+/// Keep an exponential moving average of the weights after each optimizer step
+pub(crate) struct Ema<B: AutodiffBackend, O> {
+    inner: O,
+    /// 0 to disable the EMA
+    decay: f64,
+    ema: EmaWeights<B::InnerBackend>,
+    step: usize,
+}
+
+impl<B: AutodiffBackend, O> Ema<B, O> {
+    pub(crate) fn new(inner: O, decay: f64) -> Self {
+        assert!(
+            (0. ..1.).contains(&decay),
+            "expected the EMA decay to be in [0, 1)"
+        );
+        Self {
+            inner,
+            decay,
+            ema: Default::default(),
+            step: 0,
+        }
+    }
+
+    /// The EMA weights, none if disabled
+    pub(crate) fn weights(&self) -> Option<EmaWeights<B::InnerBackend>> {
+        (self.decay > 0.).then(|| self.ema.clone())
+    }
+
+    fn update(&mut self, module: &Model<B>) {
+        if self.decay == 0. {
+            return;
+        }
+        let current = module.valid();
+        let mut ema = self.ema.lock().expect("EMA weights should not be poisoned");
+        *ema = Some(match ema.take() {
+            // the EMA restarts from the current weights when resuming
+            None => current,
+            Some(ema) => {
+                let mut collector = ParamCollector::default();
+                current.visit(&mut collector);
+                // warm up so that the random head is forgotten quickly
+                let decay = self
+                    .decay
+                    .min((1 + self.step) as f64 / (10 + self.step) as f64);
+                ema.map(&mut EmaMapper {
+                    current: collector.params,
+                    decay,
+                    phantom: PhantomData,
+                })
+            }
+        });
+    }
+}
+
+impl<B, O> Optimizer<Model<B>, B> for Ema<B, O>
+where
+    B: AutodiffBackend,
+    O: Optimizer<Model<B>, B>,
+{
+    /// the inner record and the number of steps done
+    type Record = (O::Record, usize);
+
+    fn step(&mut self, lr: LearningRate, module: Model<B>, grads: GradientsParams) -> Model<B> {
+        let module = self.inner.step(lr, module, grads);
+        self.step += 1;
+        self.update(&module);
+        module
+    }
+
+    fn to_record(&self) -> Self::Record {
+        (self.inner.to_record(), self.step)
+    }
+
+    fn load_record(mut self, record: Self::Record) -> Self {
+        self.inner = self.inner.load_record(record.0);
+        self.step = record.1;
+        self
+    }
+}
+
+/// Collect the parameters of a module by id
+struct ParamCollector<B: Backend> {
+    params: GradientsParams,
+    phantom: PhantomData<B>,
+}
+
+impl<B: Backend> Default for ParamCollector<B> {
+    fn default() -> Self {
+        Self {
+            params: GradientsParams::new(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<B: Backend> ModuleVisitor<B> for ParamCollector<B> {
+    fn visit_float<const D: usize>(&mut self, id: &ParamId, tensor: &Tensor<B, D>) {
+        self.params.register::<B, D>(*id, tensor.clone());
+    }
+}
+
+/// Blend the EMA parameters with the current ones of the same id
+struct EmaMapper<B: Backend> {
+    current: GradientsParams,
+    decay: f64,
+    phantom: PhantomData<B>,
+}
+
+impl<B: Backend> ModuleMapper<B> for EmaMapper<B> {
+    fn map_float<const D: usize>(&mut self, id: &ParamId, tensor: Tensor<B, D>) -> Tensor<B, D> {
+        match self.current.remove::<B, D>(*id) {
+            Some(current) => tensor * self.decay + current * (1. - self.decay),
+            None => tensor,
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use crate::{
+    data::{ImageBatcher, ImageData, ImageDataSet, SkipReport},
+    model::Model,
+    predict::load_tags,
+    ModelConfig, ModelType,
+};
+use burn::{
+    config::Config,
+    data::dataloader::{batcher::Batcher, DataLoaderBuilder},
+    prelude::*,
+};
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufWriter, Write as _},
+    path::PathBuf,
+};
+
+#[derive(Config, Debug)]
+pub struct EmbedConfig {
+    checkpoint: PathBuf,
+    input: PathBuf,
+    /// Feature vectors as little-endian f32, one row of `dim` values per image
+    output: PathBuf,
+    /// The json index of the rows in `output`
+    index: PathBuf,
+    tags: PathBuf,
+    /// Overrides the backbone saved in `model_config.json`
+    model: Option<ModelType>,
+    /// Overrides the input size saved in `model_config.json`, required without it
+    size: Option<usize>,
+    #[config(default = 32)]
+    batch_size: usize,
+    #[config(default = 8)]
+    num_workers: usize,
+}
+
+/// The i-th row of the embeddings file belongs to the i-th path
+#[derive(Debug, Serialize, Deserialize)]
+struct EmbedIndex {
+    dim: usize,
+    paths: Vec<PathBuf>,
+}
+
+/// The backbone of a trained model, embedding images for similarity search and clustering
+pub struct Embedder<B: Backend> {
+    model: Model<B>,
+    size: usize,
+}
+
+impl<B: Backend> Embedder<B> {
+    /// Load a checkpoint of `train` with `num_classes` tags, the backbone and input size are
+    /// read from the `model_config.json` next to it unless `model` or `size` override them
+    pub fn new(
+        model: Option<ModelType>,
+        size: Option<usize>,
+        checkpoint: PathBuf,
+        num_classes: usize,
+        device: &B::Device,
+    ) -> Self {
+        let model_config = ModelConfig::for_checkpoint(&checkpoint, model, size)
+            .expect("Model config should be found");
+        let size = model_config.size;
+        let model = model_config
+            .init::<B>(device, num_classes)
+            .load_checkpoint(checkpoint, device)
+            .expect("Failed to load checkpoint");
+        Self { model, size }
+    }
+
+    /// The feature vectors of the images in one batch, none for an empty image
+    pub fn embed(&self, images: Vec<DynamicImage>) -> Vec<Option<Vec<f32>>> {
+        let items = images
+            .into_iter()
+            .map(|img| ImageData::<B>::from_image(img, PathBuf::new(), self.size))
+            .collect::<Vec<_>>();
+        let valid = items.iter().flatten().cloned().collect::<Vec<_>>();
+        if valid.is_empty() {
+            return vec![None; items.len()];
+        }
+        let features = self
+            .model
+            .embed(ImageBatcher::new(self.size).batch(valid).datas);
+        let dim = features.dims()[1];
+        let features = features
+            .into_data()
+            .convert::<f32>()
+            .to_vec::<f32>()
+            .unwrap();
+        let mut rows = features.chunks(dim).map(<[f32]>::to_vec);
+        items
+            .iter()
+            .map(|item| item.as_ref().and_then(|_| rows.next()))
+            .collect()
+    }
+}
+
+pub fn embed<B: Backend>(config: EmbedConfig, devices: Vec<B::Device>) {
+    let all_tags = load_tags(&config.tags);
+    let Embedder { model, size } = Embedder::<B>::new(
+        config.model,
+        config.size,
+        config.checkpoint,
+        all_tags.len(),
+        &devices[0],
+    );
+
+    let dataloader_embed = DataLoaderBuilder::new(ImageBatcher::new(size))
+        .batch_size(config.batch_size)
+        .num_workers(config.num_workers)
+        .build(
+            ImageDataSet::predict(config.input, size, SkipReport::new(None))
+                .expect("Image set failed to be loaded"),
+        );
+
+    let mut output =
+        BufWriter::new(File::create(config.output).expect("The embeddings file should be created"));
+    let mut index = EmbedIndex {
+        dim: 0,
+        paths: vec![],
+    };
+    for batch in dataloader_embed
+        .iter()
+        .filter(|batch| !batch.paths.is_empty())
+    {
+        let features = model.embed(batch.datas);
+        index.dim = features.dims()[1];
+        for x in features
+            .into_data()
+            .convert::<f32>()
+            .to_vec::<f32>()
+            .unwrap()
+        {
+            output
+                .write_all(&x.to_le_bytes())
+                .expect("Failed to write the embeddings");
+        }
+        index.paths.extend(batch.paths);
+    }
+    output.flush().expect("Failed to write the embeddings");
+    serde_json::to_writer(
+        File::create(config.index).expect("The index file should be created"),
+        &index,
+    )
+    .expect("The index should be saved successfully");
+}
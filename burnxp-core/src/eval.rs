@@ -0,0 +1,374 @@
+use crate::{data::ImageBatch, model::Model};
+use burn::{data::dataloader::DataLoader, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::{fmt, io, path::Path, sync::Arc};
+use unicode_width::UnicodeWidthStr;
+
+/// Possibilities output by the model and the ground truth, both in [num_samples, num_classes]
+pub(crate) struct Inference {
+    pub num_classes: usize,
+    pub possibilities: Vec<f32>,
+    pub targets: Vec<bool>,
+}
+
+impl Inference {
+    pub(crate) fn empty(num_classes: usize) -> Self {
+        Self {
+            num_classes,
+            possibilities: vec![],
+            targets: vec![],
+        }
+    }
+
+    pub(crate) fn run<B: Backend>(
+        model: &Model<B>,
+        dataloader: Arc<dyn DataLoader<ImageBatch<B>>>,
+        num_classes: usize,
+    ) -> Self {
+        let mut inference = Self::empty(num_classes);
+        // a batch is empty if none of its images could be decoded
+        for batch in dataloader.iter().filter(|batch| !batch.paths.is_empty()) {
+            inference.extend(model.forward(batch.datas), batch.targets);
+        }
+        inference
+    }
+
+    /// # Shapes
+    ///   - Possibilities [batch_size, num_classes]
+    ///   - Targets [batch_size, num_classes]
+    pub(crate) fn extend<B: Backend>(
+        &mut self,
+        possibilities: Tensor<B, 2>,
+        targets: Tensor<B, 2, Int>,
+    ) {
+        self.possibilities.extend(
+            possibilities
+                .into_data()
+                .convert::<f32>()
+                .to_vec::<f32>()
+                .unwrap(),
+        );
+        self.targets.extend(
+            targets
+                .into_data()
+                .convert::<i64>()
+                .to_vec::<i64>()
+                .unwrap()
+                .into_iter()
+                .map(|t| t > 0),
+        );
+    }
+
+    /// The proportion of correctly predicted labels, like the `HammingScore` metric
+    pub(crate) fn hamming_score(&self, threshold: f32) -> f32 {
+        let correct = self
+            .possibilities
+            .iter()
+            .zip(self.targets.iter())
+            .filter(|(p, t)| (**p > threshold) == **t)
+            .count();
+        ratio(correct as f32, self.targets.len() as f32)
+    }
+
+    /// Possibilities and targets of the i-th tag
+    pub(crate) fn column(&self, i: usize) -> (Vec<f32>, Vec<bool>) {
+        self.possibilities
+            .iter()
+            .zip(self.targets.iter())
+            .skip(i)
+            .step_by(self.num_classes)
+            .map(|(p, t)| (*p, *t))
+            .unzip()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct TagMetrics {
+    pub name: String,
+    pub precision: f32,
+    pub recall: f32,
+    pub f1: f32,
+    /// number of positive samples
+    pub support: usize,
+    pub average_precision: f32,
+    pub true_positive: usize,
+    pub false_positive: usize,
+    pub false_negative: usize,
+    pub true_negative: usize,
+}
+
+impl TagMetrics {
+    pub(crate) fn new(
+        name: String,
+        possibilities: &[f32],
+        targets: &[bool],
+        threshold: f32,
+    ) -> Self {
+        let mut metrics = Self {
+            name,
+            ..Default::default()
+        };
+        for (p, t) in possibilities.iter().zip(targets) {
+            match (*p > threshold, *t) {
+                (true, true) => metrics.true_positive += 1,
+                (true, false) => metrics.false_positive += 1,
+                (false, true) => metrics.false_negative += 1,
+                (false, false) => metrics.true_negative += 1,
+            }
+        }
+        let tp = metrics.true_positive as f32;
+        metrics.support = metrics.true_positive + metrics.false_negative;
+        metrics.precision = ratio(tp, tp + metrics.false_positive as f32);
+        metrics.recall = ratio(tp, metrics.support as f32);
+        metrics.f1 = ratio(
+            2. * metrics.precision * metrics.recall,
+            metrics.precision + metrics.recall,
+        );
+        metrics.average_precision = average_precision(possibilities, targets);
+        metrics
+    }
+}
+
+fn ratio(a: f32, b: f32) -> f32 {
+    if b == 0. {
+        0.
+    } else {
+        a / b
+    }
+}
+
+/// The mean of the precisions at each positive sample, ranking by possibility.
+/// Samples of the same possibility are ranked together, so their order does not matter
+fn average_precision(possibilities: &[f32], targets: &[bool]) -> f32 {
+    let mut ranked = possibilities.iter().zip(targets).collect::<Vec<_>>();
+    ranked.sort_by(|a, b| b.0.total_cmp(a.0));
+    let (mut hits, mut tied_hits) = (0, 0);
+    let mut sum = 0.;
+    for (k, (p, t)) in ranked.iter().enumerate() {
+        if **t {
+            hits += 1;
+            tied_hits += 1;
+        }
+        // the precision is taken once the last sample of the same possibility is ranked
+        if ranked.get(k + 1).is_some_and(|(next, _)| *next == *p) {
+            continue;
+        }
+        sum += tied_hits as f32 * hits as f32 / (k + 1) as f32;
+        tied_hits = 0;
+    }
+    ratio(sum, hits as f32)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct EvalReport {
+    pub threshold: f32,
+    pub mean_average_precision: f32,
+    pub tags: Vec<TagMetrics>,
+}
+
+impl EvalReport {
+    /// `names` are the tag names in the order of `BitFlags`, tags without name are named by index
+    pub(crate) fn new(inference: &Inference, names: &[String], threshold: f32) -> Self {
+        let tags = (0..inference.num_classes)
+            .map(|i| {
+                let (possibilities, targets) = inference.column(i);
+                let name = names.get(i).cloned().unwrap_or_else(|| format!("tag{}", i));
+                TagMetrics::new(name, &possibilities, &targets, threshold)
+            })
+            .collect::<Vec<_>>();
+        let mean_average_precision = ratio(
+            tags.iter().map(|t| t.average_precision).sum(),
+            tags.len() as f32,
+        );
+        Self {
+            threshold,
+            mean_average_precision,
+            tags,
+        }
+    }
+
+    /// Save as `eval_report.json` and `eval_report.txt` in the directory
+    pub(crate) fn save(&self, dir: &Path) -> io::Result<()> {
+        serde_json::to_writer_pretty(std::fs::File::create(dir.join("eval_report.json"))?, self)?;
+        std::fs::write(dir.join("eval_report.txt"), self.to_string())
+    }
+}
+
+impl fmt::Display for EvalReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<16}{:<11}{:<8}{:<8}{:<8}{:<8}{:<8}{:<8}{:<8}{:<8}\n{:-<91}",
+            "Tag", "Precision", "Recall", "F1", "AP", "Support", "TP", "FP", "FN", "TN", ""
+        )?;
+        for tag in self.tags.iter() {
+            let width = UnicodeWidthStr::width_cjk(tag.name.as_str());
+            writeln!(
+                f,
+                "{}{:<width$}{:<11.3}{:<8.3}{:<8.3}{:<8.3}{:<8}{:<8}{:<8}{:<8}{:<8}",
+                tag.name,
+                "",
+                tag.precision,
+                tag.recall,
+                tag.f1,
+                tag.average_precision,
+                tag.support,
+                tag.true_positive,
+                tag.false_positive,
+                tag.false_negative,
+                tag.true_negative,
+                width = 16usize.saturating_sub(width)
+            )?;
+        }
+        write!(
+            f,
+            "{:-<91}\nthreshold: {} mAP: {:.3}",
+            "", self.threshold, self.mean_average_precision
+        )
+    }
+}
+
+/// Mean and variance over the folds
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub(crate) struct Stat {
+    pub mean: f32,
+    pub variance: f32,
+}
+
+impl Stat {
+    fn new(values: &[f32]) -> Self {
+        let mean = ratio(values.iter().sum(), values.len() as f32);
+        let variance = ratio(
+            values.iter().map(|v| (v - mean).powi(2)).sum(),
+            values.len() as f32,
+        );
+        Self { mean, variance }
+    }
+}
+
+impl fmt::Display for Stat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&format!("{:.3} ({:.4})", self.mean, self.variance))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct TagSummary {
+    pub name: String,
+    pub precision: Stat,
+    pub recall: Stat,
+    pub f1: Stat,
+    pub average_precision: Stat,
+}
+
+/// Summary of the evaluation reports of k-fold cross-validation
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct FoldSummary {
+    pub folds: usize,
+    pub mean_average_precision: Stat,
+    pub tags: Vec<TagSummary>,
+}
+
+impl FoldSummary {
+    pub(crate) fn new(reports: &[EvalReport]) -> Self {
+        let stat =
+            |f: &dyn Fn(&EvalReport) -> f32| Stat::new(&reports.iter().map(f).collect::<Vec<_>>());
+        let tags = reports
+            .first()
+            .map(|report| report.tags.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .enumerate()
+            .map(|(i, tag)| TagSummary {
+                name: tag.name.clone(),
+                precision: stat(&|r| r.tags[i].precision),
+                recall: stat(&|r| r.tags[i].recall),
+                f1: stat(&|r| r.tags[i].f1),
+                average_precision: stat(&|r| r.tags[i].average_precision),
+            })
+            .collect();
+        Self {
+            folds: reports.len(),
+            mean_average_precision: stat(&|r| r.mean_average_precision),
+            tags,
+        }
+    }
+
+    /// Save as `folds_summary.json` and `folds_summary.txt` in the directory
+    pub(crate) fn save(&self, dir: &Path) -> io::Result<()> {
+        serde_json::to_writer_pretty(std::fs::File::create(dir.join("folds_summary.json"))?, self)?;
+        std::fs::write(dir.join("folds_summary.txt"), self.to_string())
+    }
+}
+
+impl fmt::Display for FoldSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<16}{:<16}{:<16}{:<16}{:<16}\n{:-<80}",
+            "Tag", "Precision", "Recall", "F1", "AP", ""
+        )?;
+        for tag in self.tags.iter() {
+            let width = UnicodeWidthStr::width_cjk(tag.name.as_str());
+            writeln!(
+                f,
+                "{}{:<width$}{:<16}{:<16}{:<16}{:<16}",
+                tag.name,
+                "",
+                tag.precision,
+                tag.recall,
+                tag.f1,
+                tag.average_precision,
+                width = 16usize.saturating_sub(width)
+            )?;
+        }
+        write!(
+            f,
+            "{:-<80}\nmean (variance) over {} folds, mAP: {}",
+            "", self.folds, self.mean_average_precision
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-6, "{} != {}", value, expected);
+    }
+
+    #[test]
+    fn average_precision_ranked() {
+        let possibilities = [0.9, 0.8, 0.7, 0.6];
+        assert_close(
+            average_precision(&possibilities, &[true, true, false, false]),
+            1.,
+        );
+        // precisions 1/2 and 2/3 at the positives
+        assert_close(
+            average_precision(&possibilities, &[false, true, true, false]),
+            (1. / 2. + 2. / 3.) / 2.,
+        );
+    }
+
+    #[test]
+    fn average_precision_ties() {
+        let possibilities = [0.9, 0.5, 0.5, 0.1];
+        let first = average_precision(&possibilities, &[true, true, false, false]);
+        let second = average_precision(&possibilities, &[true, false, true, false]);
+        // the positive tied with a negative is at precision 2/3
+        assert_close(first, (1. + 2. / 3.) / 2.);
+        assert_close(second, first);
+        assert_close(
+            average_precision(&[0.5; 4], &[true, false, false, true]),
+            0.5,
+        );
+    }
+
+    #[test]
+    fn average_precision_all_negative() {
+        assert_close(average_precision(&[0.9, 0.1], &[false, false]), 0.);
+        assert_close(average_precision(&[], &[]), 0.);
+    }
+}
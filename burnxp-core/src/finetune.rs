@@ -0,0 +1,94 @@
+use crate::model::Model;
+use burn::{
+    module::{ModuleVisitor, ParamId},
+    optim::{GradientsParams, Optimizer},
+    prelude::*,
+    tensor::backend::AutodiffBackend,
+    LearningRate,
+};
+use std::marker::PhantomData;
+
+/// Staged fine-tuning around an optimizer: only `linear` is trained while the backbone is frozen
+/// for the first steps, then the backbone is unfrozen with a discriminative learning rate
+pub(crate) struct FineTune<O> {
+    inner: O,
+    /// optimizer steps before unfreezing the backbone
+    freeze_steps: usize,
+    /// learning rate of the backbone relative to `linear`
+    backbone_lr_factor: f64,
+    step: usize,
+}
+
+impl<O> FineTune<O> {
+    pub(crate) fn new(inner: O, freeze_steps: usize, backbone_lr_factor: f64) -> Self {
+        Self {
+            inner,
+            freeze_steps,
+            backbone_lr_factor,
+            step: 0,
+        }
+    }
+}
+
+impl<B, O> Optimizer<Model<B>, B> for FineTune<O>
+where
+    B: AutodiffBackend,
+    O: Optimizer<Model<B>, B>,
+{
+    /// the inner record and the number of steps done, so that resuming keeps the stage
+    type Record = (O::Record, usize);
+
+    fn step(&mut self, lr: LearningRate, module: Model<B>, mut grads: GradientsParams) -> Model<B> {
+        self.step += 1;
+        // the frozen backbone is detached, so it has no gradients
+        let module = if self.step <= self.freeze_steps || self.backbone_lr_factor == 1. {
+            self.inner.step(lr, module, grads)
+        } else {
+            let backbone_grads = module.split_backbone_grads(&mut grads);
+            let module = self.inner.step(lr, module, grads);
+            // the inner optimizer keeps the state per parameter, so stepping twice is fine
+            self.inner
+                .step(lr * self.backbone_lr_factor, module, backbone_grads)
+        };
+        module.freeze(self.step < self.freeze_steps)
+    }
+
+    fn to_record(&self) -> Self::Record {
+        (self.inner.to_record(), self.step)
+    }
+
+    fn load_record(mut self, record: Self::Record) -> Self {
+        self.inner = self.inner.load_record(record.0);
+        self.step = record.1;
+        self
+    }
+}
+
+/// Move the gradients of the visited parameters into another `GradientsParams`
+pub(crate) struct GradientsSplitter<'a, B: AutodiffBackend> {
+    grads: &'a mut GradientsParams,
+    split: GradientsParams,
+    phantom: PhantomData<B>,
+}
+
+impl<'a, B: AutodiffBackend> GradientsSplitter<'a, B> {
+    pub(crate) fn new(grads: &'a mut GradientsParams) -> Self {
+        Self {
+            grads,
+            split: GradientsParams::new(),
+            phantom: PhantomData,
+        }
+    }
+
+    pub(crate) fn finish(self) -> GradientsParams {
+        self.split
+    }
+}
+
+impl<B: AutodiffBackend> ModuleVisitor<B> for GradientsSplitter<'_, B> {
+    fn visit_float<const D: usize>(&mut self, id: &ParamId, _tensor: &Tensor<B, D>) {
+        if let Some(grad) = self.grads.remove::<B::InnerBackend, D>(*id) {
+            self.split.register::<B::InnerBackend, D>(*id, grad);
+        }
+    }
+}
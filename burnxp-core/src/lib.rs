@@ -1,15 +1,44 @@
-#![cfg(any(feature = "tch", feature = "candle"))]
+#![cfg(any(feature = "tch", feature = "candle", feature = "ndarray"))]
 
-#[cfg(all(feature = "tch", feature = "candle"))]
-compile_error!("features `tch` and `candle` are mutually exclusive");
+#[cfg(any(
+    all(feature = "tch", feature = "candle"),
+    all(feature = "tch", feature = "ndarray"),
+    all(feature = "candle", feature = "ndarray")
+))]
+compile_error!("features `tch`, `candle` and `ndarray` are mutually exclusive");
 
+#[cfg(all(feature = "ndarray", feature = "f16"))]
+compile_error!("feature `f16` is not supported by `ndarray`");
+
+mod augment;
+mod cache;
+mod calibrate;
 mod cli;
 mod data;
+mod ema;
+mod embed;
+mod eval;
+mod finetune;
+mod loss;
+mod metrics;
 mod model;
+mod optim;
 mod predict;
+mod serve;
+mod sweep;
 mod train;
+mod vit;
 
+pub use augment::AugmentConfig;
+pub use cache::{build_cache, clean_cache};
+pub use calibrate::{calibrate, CalibrateConfig};
 pub use cli::run;
-pub use model::{ModelConfig, ResNetType};
+pub use ema::BestMetric;
+pub use embed::{embed, EmbedConfig, Embedder};
+pub use loss::{LossConfig, LossType};
+pub use model::{ModelConfig, ModelType};
+pub use optim::{OptimizerConfig, OptimizerType, ScheduleConfig, ScheduleType};
 pub use predict::{predict, Output, PredictConfig};
-pub use train::{train, TrainingConfig};
+pub use serve::{serve, ServeConfig};
+pub use sweep::{sweep, SearchType, SweepConfig};
+pub use train::{resume, train, train_folds, DivideConfig, TrainingConfig};
@@ -0,0 +1,116 @@
+use burn::{module::Ignored, prelude::*};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, ValueEnum)]
+pub enum LossType {
+    /// Binary cross entropy
+    #[default]
+    Bce,
+    /// Focal loss, down-weighting the well-classified tags by (1 - p_t)^gamma
+    Focal,
+    /// Asymmetric loss, focusing less on the negatives and discarding the easy ones
+    Asymmetric,
+}
+
+#[derive(Config, Debug)]
+pub struct LossConfig {
+    #[config(default = "LossType::Bce")]
+    loss_type: LossType,
+    /// Label smoothing, 0 to disable
+    #[config(default = 0.1)]
+    smoothing: f32,
+    /// Focusing parameter of the focal loss
+    #[config(default = 2.0)]
+    focal_gamma: f32,
+    /// Weight of the positives of the focal loss in [0, 1], negatives are weighted 1 - alpha
+    focal_alpha: Option<f32>,
+    /// Focusing parameter of the asymmetric loss for the positives
+    #[config(default = 0.0)]
+    asl_gamma_pos: f32,
+    /// Focusing parameter of the asymmetric loss for the negatives
+    #[config(default = 4.0)]
+    asl_gamma_neg: f32,
+    /// Possibilities of the negatives below the margin are discarded by the asymmetric loss
+    #[config(default = 0.05)]
+    asl_clip: f32,
+}
+
+#[derive(Module, Debug)]
+pub(crate) struct Loss<B: Backend> {
+    /// weights of each tag computed by the divider
+    weights: Option<Tensor<B, 1>>,
+    config: Ignored<LossConfig>,
+}
+
+impl LossConfig {
+    pub(crate) fn init<B: Backend>(
+        &self,
+        weights: Option<Vec<f32>>,
+        device: &B::Device,
+    ) -> Loss<B> {
+        assert!(
+            (0. ..1.).contains(&self.smoothing),
+            "expected the smoothing to be in [0, 1)"
+        );
+        if let Some(alpha) = self.focal_alpha {
+            assert!(
+                (0. ..=1.).contains(&alpha),
+                "expected the focal alpha to be in [0, 1]"
+            );
+        }
+        Loss {
+            weights: weights.map(|w| Tensor::from_floats(&w[..], device)),
+            config: Ignored(self.clone()),
+        }
+    }
+}
+
+impl<B: Backend> Loss<B> {
+    /// Accept float targets in [0, 1], e.g. mixed ones
+    /// # Shapes
+    ///   - Possibilities [batch_size, num_classes], after sigmoid
+    ///   - Targets [batch_size, num_classes]
+    ///   - Output [1]
+    pub(crate) fn forward(
+        &self,
+        possibilities: Tensor<B, 2>,
+        targets: Tensor<B, 2>,
+    ) -> Tensor<B, 1> {
+        let config = &self.config.0;
+        let shape = targets.dims();
+        let targets = if config.smoothing > 0. {
+            targets * (1. - config.smoothing) + config.smoothing / shape[1] as f32
+        } else {
+            targets
+        };
+        // clamp at -100 like burn to avoid undefined values
+        let log_pos = possibilities.clone().log().clamp_min(-100.);
+        let log_neg = possibilities.clone().neg().log1p().clamp_min(-100.);
+        let mut loss = match config.loss_type {
+            LossType::Bce => targets.clone().neg() * log_pos - (targets.neg() + 1.) * log_neg,
+            LossType::Focal => {
+                let (alpha_pos, alpha_neg) = config
+                    .focal_alpha
+                    .map_or((1., 1.), |alpha| (alpha, 1. - alpha));
+                let pos = (possibilities.clone().neg() + 1.).powf_scalar(config.focal_gamma)
+                    * log_pos
+                    * alpha_pos;
+                let neg = possibilities.powf_scalar(config.focal_gamma) * log_neg * alpha_neg;
+                targets.clone().neg() * pos - (targets.neg() + 1.) * neg
+            }
+            LossType::Asymmetric => {
+                // shift the negatives' possibilities by the margin
+                let shifted = (possibilities.clone() - config.asl_clip).clamp_min(0.);
+                let log_neg = shifted.clone().neg().log1p().clamp_min(-100.);
+                let pos = (possibilities.neg() + 1.).powf_scalar(config.asl_gamma_pos) * log_pos;
+                let neg = shifted.powf_scalar(config.asl_gamma_neg) * log_neg;
+                targets.clone().neg() * pos - (targets.neg() + 1.) * neg
+            }
+        };
+        if let Some(weights) = &self.weights {
+            loss = loss * weights.clone().expand(shape);
+        }
+        loss.mean()
+    }
+}
@@ -0,0 +1,246 @@
+use crate::{
+    ema::Selector,
+    eval::{EvalReport, Inference, TagMetrics},
+    train::TrainingConfig,
+};
+use burn::{
+    prelude::*,
+    train::{
+        logger::{FileMetricLogger, MetricLogger},
+        metric::{Adaptor, Metric, MetricEntry, MetricMetadata, NumericEntry},
+        MultiLabelClassificationOutput,
+    },
+};
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// `metrics.jsonl` in the artifact directory, one json per line: the resolved training config
+/// each time training starts or resumes, then an entry per epoch and split
+pub(crate) struct MetricsLog {
+    file: File,
+}
+
+#[derive(Serialize)]
+struct ConfigEntry<'a> {
+    config: &'a TrainingConfig,
+}
+
+#[derive(Serialize)]
+struct EpochEntry {
+    epoch: usize,
+    split: &'static str,
+    loss: Option<f64>,
+    hamming_score: Option<f64>,
+    learning_rate: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<TagMetrics>>,
+}
+
+impl MetricsLog {
+    pub(crate) fn open(artifact_dir: &Path, config: &TrainingConfig) -> Arc<Mutex<Self>> {
+        let mut log = Self {
+            file: OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(artifact_dir.join("metrics.jsonl"))
+                .expect("Metrics log should be created successfully"),
+        };
+        log.write(&ConfigEntry { config });
+        Arc::new(Mutex::new(log))
+    }
+
+    fn write(&mut self, entry: &impl Serialize) {
+        serde_json::to_writer(&mut self.file, entry)
+            .expect("Metrics should be logged successfully");
+        writeln!(self.file).expect("Metrics should be logged successfully");
+    }
+}
+
+/// Burn's file logger of a split, also logging the epoch means of the metrics to `MetricsLog`
+pub(crate) struct JsonlLogger {
+    inner: FileMetricLogger,
+    split: &'static str,
+    log: Arc<Mutex<MetricsLog>>,
+    /// weighted sums and counts of the loss, hamming score and learning rate of the epoch
+    sums: [(f64, usize); 3],
+    /// the validation outputs, tag names and threshold of the per-tag metrics
+    tags: Option<(LastInference, Vec<String>, f32)>,
+}
+
+impl JsonlLogger {
+    pub(crate) fn new(
+        artifact_dir: &Path,
+        split: &'static str,
+        log: Arc<Mutex<MetricsLog>>,
+    ) -> Self {
+        Self {
+            inner: FileMetricLogger::new(
+                artifact_dir
+                    .join(split)
+                    .to_str()
+                    .expect("Artifact directory should be valid UTF-8"),
+            ),
+            split,
+            log,
+            sums: Default::default(),
+            tags: None,
+        }
+    }
+
+    /// Log the per-tag metrics of each validation pass
+    pub(crate) fn with_tags(
+        mut self,
+        last: LastInference,
+        names: Vec<String>,
+        threshold: f32,
+    ) -> Self {
+        self.tags = Some((last, names, threshold));
+        self
+    }
+}
+
+impl MetricLogger for JsonlLogger {
+    fn log(&mut self, item: &MetricEntry) {
+        self.inner.log(item);
+        let index = match item.name.as_str() {
+            "Loss" => 0,
+            name if name.starts_with("Hamming Score") => 1,
+            "Learning Rate" => 2,
+            _ => return,
+        };
+        // serialized as `value` or `value,count`
+        let mut parts = item.serialize.split(',');
+        let Some(Ok(value)) = parts.next().map(str::parse::<f64>) else {
+            return;
+        };
+        let count = parts.next().and_then(|c| c.parse().ok()).unwrap_or(1);
+        let (sum, total) = &mut self.sums[index];
+        *sum += value * count as f64;
+        *total += count;
+    }
+
+    fn end_epoch(&mut self, epoch: usize) {
+        self.inner.end_epoch(epoch);
+        let [loss, hamming_score, learning_rate] = std::mem::take(&mut self.sums)
+            .map(|(sum, total)| Some(sum / total as f64).filter(|_| total > 0));
+        let tags = self.tags.as_ref().and_then(|(last, names, threshold)| {
+            // the logger runs behind the learner, which may have validated another epoch
+            let (_, inference) = last.get().filter(|(validated, _)| *validated == epoch)?;
+            Some(EvalReport::new(&inference, names, *threshold).tags)
+        });
+        let mut log = self.log.lock().expect("Metrics log should not be poisoned");
+        log.write(&EpochEntry {
+            epoch,
+            split: self.split,
+            loss,
+            hamming_score,
+            learning_rate,
+            tags,
+        });
+    }
+
+    fn read_numeric(&mut self, name: &str, epoch: usize) -> Result<Vec<NumericEntry>, String> {
+        self.inner.read_numeric(name, epoch)
+    }
+}
+
+/// The epoch and inference of the learner's last validation pass
+#[derive(Clone, Default)]
+pub(crate) struct LastInference(Arc<Mutex<Option<(usize, Arc<Inference>)>>>);
+
+impl LastInference {
+    pub(crate) fn get(&self) -> Option<(usize, Arc<Inference>)> {
+        self.0
+            .lock()
+            .expect("Last inference should not be poisoned")
+            .clone()
+    }
+
+    fn set(&self, epoch: usize, inference: Arc<Inference>) {
+        *self
+            .0
+            .lock()
+            .expect("Last inference should not be poisoned") = Some((epoch, inference));
+    }
+}
+
+pub(crate) struct ValidOutputsInput<B: Backend> {
+    outputs: Tensor<B, 2>,
+    targets: Tensor<B, 2, Int>,
+}
+
+impl<B: Backend> Adaptor<ValidOutputsInput<B>> for MultiLabelClassificationOutput<B> {
+    fn adapt(&self) -> ValidOutputsInput<B> {
+        ValidOutputsInput {
+            outputs: self.output.clone(),
+            targets: self.targets.clone(),
+        }
+    }
+}
+
+/// Keep the outputs of the validation steps on the device until the learner clears the metric
+/// at the end of the pass, then select the best model and share the inference with the logger
+pub(crate) struct ValidOutputs<B: Backend> {
+    num_classes: usize,
+    epoch: usize,
+    outputs: Vec<Tensor<B, 2>>,
+    targets: Vec<Tensor<B, 2, Int>>,
+    last: LastInference,
+    selector: Arc<Mutex<Selector<B>>>,
+}
+
+impl<B: Backend> ValidOutputs<B> {
+    pub(crate) fn new(
+        num_classes: usize,
+        last: LastInference,
+        selector: Arc<Mutex<Selector<B>>>,
+    ) -> Self {
+        Self {
+            num_classes,
+            epoch: 0,
+            outputs: vec![],
+            targets: vec![],
+            last,
+            selector,
+        }
+    }
+}
+
+impl<B: Backend> Metric for ValidOutputs<B> {
+    const NAME: &'static str = "Validation Outputs";
+
+    type Input = ValidOutputsInput<B>;
+
+    fn update(&mut self, item: &Self::Input, metadata: &MetricMetadata) -> MetricEntry {
+        self.epoch = metadata.epoch;
+        self.outputs.push(item.outputs.clone());
+        self.targets.push(item.targets.clone());
+        let count = self.outputs.iter().map(|o| o.dims()[0]).sum::<usize>();
+        MetricEntry::new(
+            Self::NAME.to_string(),
+            format!("{} images", count),
+            count.to_string(),
+        )
+    }
+
+    fn clear(&mut self) {
+        if self.outputs.is_empty() {
+            return;
+        }
+        let mut inference = Inference::empty(self.num_classes);
+        inference.extend(
+            Tensor::cat(std::mem::take(&mut self.outputs), 0),
+            Tensor::cat(std::mem::take(&mut self.targets), 0),
+        );
+        self.selector
+            .lock()
+            .expect("Selector should not be poisoned")
+            .select(self.epoch, &inference);
+        self.last.set(self.epoch, Arc::new(inference));
+    }
+}
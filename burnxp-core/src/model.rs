@@ -1,117 +1,307 @@
 use crate::data::ImageBatch;
+use crate::finetune::GradientsSplitter;
+use crate::loss::{Loss, LossConfig};
+use crate::vit::{Vit, VitConfig};
+use burn::module::{AutodiffModule, Ignored};
+use burn::optim::GradientsParams;
 use burn::prelude::*;
+use burn::record::{CompactRecorder, Record, Recorder, RecorderError};
 use burn::tensor::backend::AutodiffBackend;
 use burn::train::{MultiLabelClassificationOutput, TrainOutput, TrainStep, ValidStep};
 use clap::builder::OsStr;
 use clap::ValueEnum;
-use nn::loss::{BinaryCrossEntropyLoss, BinaryCrossEntropyLossConfig};
+use mobilenetv2_burn::model::{
+    mobilenetv2::{MobileNetV2, MobileNetV2Config},
+    weights,
+};
 use nn::{LeakyRelu, LeakyReluConfig, Linear, LinearConfig, Sigmoid};
 use resnet_burn::weights::{ResNet101, ResNet152, ResNet18, ResNet34, ResNet50};
 use resnet_burn::ResNet;
 use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Number of features output by the backbones
+const NUM_FEATURES: usize = 1000;
+
+/// Input size above which vit-tiny becomes prohibitively slow and memory hungry
+const VIT_MAX_SIZE: usize = 384;
+
+/// The feature extractor chosen by `ModelType`
+#[derive(Module, Debug)]
+pub(crate) enum Backbone<B: Backend> {
+    ResNet(ResNet<B>),
+    MobileNetV2(MobileNetV2<B>),
+    Vit(Vit<B>),
+}
+
+impl<B: Backend> Backbone<B> {
+    fn forward(&self, datas: Tensor<B, 4>) -> Tensor<B, 2> {
+        match self {
+            Self::ResNet(resnet) => resnet.forward(datas),
+            Self::MobileNetV2(mobilenet) => mobilenet.forward(datas),
+            Self::Vit(vit) => vit.forward(datas),
+        }
+    }
+}
 
 #[derive(Module, Debug)]
 pub(crate) struct Model<B: Backend> {
-    resnet: ResNet<B>,
+    backbone: Backbone<B>,
     relu: LeakyRelu,
     linear: Linear<B>,
     sigmoid: Sigmoid,
-    loss: BinaryCrossEntropyLoss<B>,
+    loss: Loss<B>,
+    /// whether the backbone is frozen for the next training steps
+    frozen: Ignored<bool>,
 }
 
 impl<B: Backend> Model<B> {
     /// # Shapes
-    ///   - Images [batch_size, 3, SIZE, SIZE]
+    ///   - Images [batch_size, 3, size, size]
     ///   - Output [batch_size, num_classes]
     pub fn forward(&self, datas: Tensor<B, 4>) -> Tensor<B, 2> {
-        let datas = datas.to_device(&self.devices()[0]);
-        let x = self.resnet.forward(datas); // [batch_size, 1000]
-        let x = self.relu.forward(x);
+        self.classify(self.embed(datas))
+    }
+
+    /// # Shapes
+    ///   - Features [batch_size, 1000]
+    ///   - Output [batch_size, num_classes]
+    fn classify(&self, features: Tensor<B, 2>) -> Tensor<B, 2> {
+        let x = self.relu.forward(features);
         let x = self.linear.forward(x); // [batch_size, num_classes]
         self.sigmoid.forward(x)
     }
 
+    /// The backbone features, useful for similarity search and clustering
+    /// # Shapes
+    ///   - Images [batch_size, 3, size, size]
+    ///   - Output [batch_size, 1000]
+    pub(crate) fn embed(&self, datas: Tensor<B, 4>) -> Tensor<B, 2> {
+        self.backbone.forward(datas.to_device(&self.devices()[0]))
+    }
+
+    /// Freeze or unfreeze the backbone for the next training steps
+    pub(crate) fn freeze(mut self, frozen: bool) -> Self {
+        self.frozen = Ignored(frozen);
+        self
+    }
+
+    /// Load a checkpoint saved by `train`, including those saved before the backbone was
+    /// selectable, which hold a bare ResNet
+    pub(crate) fn load_checkpoint(
+        self,
+        path: PathBuf,
+        device: &B::Device,
+    ) -> Result<Self, RecorderError> {
+        let recorder = CompactRecorder::new();
+        let err = match recorder.load::<ModelRecord<B>>(path.clone(), device) {
+            Ok(record) => return Ok(self.load_record(record)),
+            Err(err) => err,
+        };
+        match (
+            self.backbone,
+            recorder.load::<LegacyModelRecord<B>>(path, device),
+        ) {
+            (Backbone::ResNet(resnet), Ok(legacy)) => Ok(Model {
+                backbone: Backbone::ResNet(resnet.load_record(legacy.resnet)),
+                linear: self.linear.load_record(legacy.linear),
+                ..self
+            }),
+            _ => Err(err),
+        }
+    }
+
     fn forward_multilabelclassification(
         &self,
+        output: Tensor<B, 2>,
         batch: ImageBatch<B>,
     ) -> MultiLabelClassificationOutput<B> {
-        let output = self.forward(batch.datas);
         let taget = batch.targets.to_device(&self.devices()[0]);
-        let loss = self.loss.forward(output.clone(), taget.clone());
+        let soft_targets = batch.soft_targets.to_device(&self.devices()[0]);
+        let loss = self.loss.forward(output.clone(), soft_targets);
         MultiLabelClassificationOutput::new(loss, output, taget)
     }
 }
 
+impl<B: AutodiffBackend> Model<B> {
+    /// Take the gradients of the backbone out of `grads`, leaving those of `linear`
+    pub(crate) fn split_backbone_grads(&self, grads: &mut GradientsParams) -> GradientsParams {
+        let mut splitter = GradientsSplitter::new(grads);
+        self.backbone.visit(&mut splitter);
+        splitter.finish()
+    }
+
+    /// The backbone features, computed in inference mode while the backbone is frozen, so that
+    /// its BatchNorm statistics are kept and no gradient flows into it
+    fn embed_train(&self, datas: Tensor<B, 4>) -> Tensor<B, 2> {
+        if !self.frozen.0 {
+            return self.embed(datas);
+        }
+        let datas = datas.to_device(&self.devices()[0]).inner();
+        Tensor::from_inner(self.backbone.valid().forward(datas))
+    }
+}
+
+/// The trained weights of a checkpoint saved before the backbone was selectable, the other
+/// fields are left out of the record and ignored when loading
+#[derive(Record)]
+struct LegacyModelRecord<B: Backend> {
+    resnet: <ResNet<B> as Module<B>>::Record,
+    linear: <Linear<B> as Module<B>>::Record,
+}
+
 impl<B: AutodiffBackend> TrainStep<ImageBatch<B>, MultiLabelClassificationOutput<B>> for Model<B> {
     fn step(&self, batch: ImageBatch<B>) -> TrainOutput<MultiLabelClassificationOutput<B>> {
-        let classify = self.forward_multilabelclassification(batch);
+        let output = self.classify(self.embed_train(batch.datas.clone()));
+        let classify = self.forward_multilabelclassification(output, batch);
         TrainOutput::new(self, classify.loss.backward(), classify)
     }
 }
 
 impl<B: Backend> ValidStep<ImageBatch<B>, MultiLabelClassificationOutput<B>> for Model<B> {
     fn step(&self, batch: ImageBatch<B>) -> MultiLabelClassificationOutput<B> {
-        self.forward_multilabelclassification(batch)
+        let output = self.forward(batch.datas.clone());
+        self.forward_multilabelclassification(output, batch)
     }
 }
 
 #[derive(Config, Debug)]
 pub struct ModelConfig {
-    rnn_type: ResNetType,
+    rnn_type: ModelType,
     #[config(default = true)]
     download: bool,
     loss_weights: Option<Vec<f32>>,
+    /// Images are resized to size x size as input
+    #[config(default = 720)]
+    pub(crate) size: usize,
+    #[config(default = "LossConfig::new()")]
+    loss: LossConfig,
 }
 
 impl ModelConfig {
+    /// The config saved by `train` as `model_config.json` in the directory of the checkpoint
+    /// or its parent. `model` and `size` override the saved ones, the size is required if the
+    /// config is not found
+    pub(crate) fn for_checkpoint(
+        checkpoint: &Path,
+        model: Option<ModelType>,
+        size: Option<usize>,
+    ) -> io::Result<Self> {
+        let saved = checkpoint
+            .ancestors()
+            .skip(1)
+            .take(2)
+            .find_map(|dir| Self::load(dir.join("model_config.json")).ok());
+        let mut config = match saved {
+            Some(config) => config,
+            None if size.is_some() => Self::new(ModelType::default()),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "model_config.json not found next to the checkpoint, please give `--size`",
+                ))
+            }
+        };
+        if let Some(model) = model {
+            config.rnn_type = model;
+        }
+        if let Some(size) = size {
+            config.size = size;
+        }
+        Ok(config.with_download(false))
+    }
+
     pub(crate) fn init<B: Backend>(&self, device: &B::Device, num_classes: usize) -> Model<B> {
-        let resnet = if self.download {
-            match self.rnn_type {
-                ResNetType::Layer18 => ResNet::resnet18_pretrained(ResNet18::ImageNet1kV1, device),
-                ResNetType::Layer34 => ResNet::resnet34_pretrained(ResNet34::ImageNet1kV1, device),
-                ResNetType::Layer50 => ResNet::resnet50_pretrained(ResNet50::ImageNet1kV2, device),
-                ResNetType::Layer101 => {
-                    ResNet::resnet101_pretrained(ResNet101::ImageNet1kV2, device)
+        let backbone = match self.rnn_type {
+            ModelType::MobileNetV2 if self.download => Backbone::MobileNetV2(
+                MobileNetV2::pretrained(weights::MobileNetV2::ImageNet1kV2, device)
+                    .expect("Failed to download the model"),
+            ),
+            ModelType::MobileNetV2 => Backbone::MobileNetV2(MobileNetV2Config::new().init(device)),
+            ModelType::VitTiny => {
+                if self.download {
+                    eprintln!("No pretrained weights for vit-tiny, training from scratch");
                 }
-                ResNetType::Layer152 => {
-                    ResNet::resnet152_pretrained(ResNet152::ImageNet1kV2, device)
+                if self.size > VIT_MAX_SIZE {
+                    eprintln!(
+                        "The attention of vit-tiny is quadratic in the number of patches, \
+                        consider an input size up to {}",
+                        VIT_MAX_SIZE
+                    );
                 }
+                Backbone::Vit(VitConfig::new(self.size, NUM_FEATURES).init(device))
             }
-            .expect("Failed to download the model")
-        } else {
-            match self.rnn_type {
-                ResNetType::Layer18 => ResNet::resnet18(1000, device),
-                ResNetType::Layer34 => ResNet::resnet34(1000, device),
-                ResNetType::Layer50 => ResNet::resnet50(1000, device),
-                ResNetType::Layer101 => ResNet::resnet101(1000, device),
-                ResNetType::Layer152 => ResNet::resnet152(1000, device),
-            }
+            ModelType::Layer18 if self.download => Backbone::ResNet(
+                ResNet::resnet18_pretrained(ResNet18::ImageNet1kV1, device)
+                    .expect("Failed to download the model"),
+            ),
+            ModelType::Layer18 => Backbone::ResNet(ResNet::resnet18(NUM_FEATURES, device)),
+            ModelType::Layer34 if self.download => Backbone::ResNet(
+                ResNet::resnet34_pretrained(ResNet34::ImageNet1kV1, device)
+                    .expect("Failed to download the model"),
+            ),
+            ModelType::Layer34 => Backbone::ResNet(ResNet::resnet34(NUM_FEATURES, device)),
+            ModelType::Layer50 if self.download => Backbone::ResNet(
+                ResNet::resnet50_pretrained(ResNet50::ImageNet1kV2, device)
+                    .expect("Failed to download the model"),
+            ),
+            ModelType::Layer50 => Backbone::ResNet(ResNet::resnet50(NUM_FEATURES, device)),
+            ModelType::Layer101 if self.download => Backbone::ResNet(
+                ResNet::resnet101_pretrained(ResNet101::ImageNet1kV2, device)
+                    .expect("Failed to download the model"),
+            ),
+            ModelType::Layer101 => Backbone::ResNet(ResNet::resnet101(NUM_FEATURES, device)),
+            ModelType::Layer152 if self.download => Backbone::ResNet(
+                ResNet::resnet152_pretrained(ResNet152::ImageNet1kV2, device)
+                    .expect("Failed to download the model"),
+            ),
+            ModelType::Layer152 => Backbone::ResNet(ResNet::resnet152(NUM_FEATURES, device)),
         };
         Model {
-            resnet,
+            backbone,
             relu: LeakyReluConfig::new().init(),
-            linear: LinearConfig::new(1000, num_classes).init(device),
+            linear: LinearConfig::new(NUM_FEATURES, num_classes).init(device),
             sigmoid: Sigmoid,
-            loss: BinaryCrossEntropyLossConfig::new()
-                .with_smoothing(Some(0.1))
-                .with_weights(self.loss_weights.clone())
-                .init(device),
+            loss: self.loss.init(self.loss_weights.clone(), device),
+            frozen: Ignored(false),
         }
     }
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize, ValueEnum)]
-pub enum ResNetType {
+pub enum ModelType {
     Layer18 = 18,
     Layer34 = 34,
     Layer50 = 50,
     #[default]
     Layer101 = 101,
     Layer152 = 152,
+    /// Lightweight CNN for fast CPU inference
+    #[value(name = "mobilenetv2")]
+    MobileNetV2,
+    /// ViT-Tiny style transformer trained from scratch, the input size should be divisible by 16
+    /// and is 224 by default
+    VitTiny,
+}
+
+impl ModelType {
+    /// Input size used by `train` unless `--size` is given
+    pub(crate) fn default_size(&self) -> usize {
+        match self {
+            Self::VitTiny => 224,
+            _ => 720,
+        }
+    }
 }
 
-impl From<ResNetType> for OsStr {
-    fn from(value: ResNetType) -> Self {
-        format!("layer{:?}", value as usize).into()
+impl From<ModelType> for OsStr {
+    fn from(value: ModelType) -> Self {
+        value
+            .to_possible_value()
+            .expect("no variant is skipped")
+            .get_name()
+            .to_string()
+            .into()
     }
 }
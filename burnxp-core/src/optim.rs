@@ -0,0 +1,444 @@
+use burn::{
+    lr_scheduler::LrScheduler,
+    module::AutodiffModule,
+    optim::{
+        decay::WeightDecayConfig, momentum::MomentumConfig, AdamConfig, AdamWConfig,
+        GradientsParams, Optimizer, SgdConfig,
+    },
+    prelude::*,
+    tensor::backend::AutodiffBackend,
+    LearningRate,
+};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    f64::consts::PI,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, ValueEnum)]
+pub enum OptimizerType {
+    #[default]
+    Adam,
+    /// Adam with decoupled weight decay
+    #[value(name = "adamw")]
+    AdamW,
+    /// SGD with momentum
+    Sgd,
+}
+
+#[derive(Config, Debug)]
+pub struct OptimizerConfig {
+    #[config(default = "OptimizerType::Adam")]
+    optimizer_type: OptimizerType,
+    /// Weight decay, 0 to disable. Coupled L2 penalty for Adam and SGD, decoupled for AdamW
+    #[config(default = 0.0)]
+    weight_decay: f32,
+    /// Momentum of SGD, 0 to disable
+    #[config(default = 0.9)]
+    momentum: f64,
+    /// Nesterov momentum of SGD
+    #[config(default = false)]
+    nesterov: bool,
+}
+
+/// One of the optimizers, chosen at runtime by `OptimizerConfig`
+pub(crate) enum AnyOptimizer<A, W, S> {
+    Adam(A),
+    AdamW(W),
+    Sgd(S),
+}
+
+impl OptimizerConfig {
+    pub(crate) fn init<B: AutodiffBackend, M: AutodiffModule<B>>(
+        &self,
+    ) -> AnyOptimizer<impl Optimizer<M, B>, impl Optimizer<M, B>, impl Optimizer<M, B>> {
+        let weight_decay =
+            Some(WeightDecayConfig::new(self.weight_decay)).filter(|_| self.weight_decay > 0.);
+        match self.optimizer_type {
+            OptimizerType::Adam => {
+                AnyOptimizer::Adam(AdamConfig::new().with_weight_decay(weight_decay).init())
+            }
+            OptimizerType::AdamW => AnyOptimizer::AdamW(
+                AdamWConfig::new()
+                    .with_weight_decay(self.weight_decay)
+                    .init(),
+            ),
+            OptimizerType::Sgd => AnyOptimizer::Sgd(
+                SgdConfig::new()
+                    .with_weight_decay(weight_decay)
+                    .with_momentum(Some(
+                        MomentumConfig::new()
+                            .with_momentum(self.momentum)
+                            .with_dampening(0.)
+                            .with_nesterov(self.nesterov),
+                    ))
+                    .init(),
+            ),
+        }
+    }
+}
+
+impl<M, B, A, W, S> Optimizer<M, B> for AnyOptimizer<A, W, S>
+where
+    M: AutodiffModule<B>,
+    B: AutodiffBackend,
+    A: Optimizer<M, B>,
+    W: Optimizer<M, B>,
+    S: Optimizer<M, B>,
+{
+    /// only the record of the chosen optimizer is `Some`
+    type Record = (Option<A::Record>, Option<W::Record>, Option<S::Record>);
+
+    fn step(&mut self, lr: LearningRate, module: M, grads: GradientsParams) -> M {
+        match self {
+            Self::Adam(optim) => optim.step(lr, module, grads),
+            Self::AdamW(optim) => optim.step(lr, module, grads),
+            Self::Sgd(optim) => optim.step(lr, module, grads),
+        }
+    }
+
+    fn to_record(&self) -> Self::Record {
+        match self {
+            Self::Adam(optim) => (Some(optim.to_record()), None, None),
+            Self::AdamW(optim) => (None, Some(optim.to_record()), None),
+            Self::Sgd(optim) => (None, None, Some(optim.to_record())),
+        }
+    }
+
+    fn load_record(self, record: Self::Record) -> Self {
+        match (self, record) {
+            (Self::Adam(optim), (Some(record), _, _)) => Self::Adam(optim.load_record(record)),
+            (Self::AdamW(optim), (_, Some(record), _)) => Self::AdamW(optim.load_record(record)),
+            (Self::Sgd(optim), (_, _, Some(record))) => Self::Sgd(optim.load_record(record)),
+            _ => panic!("expected the optimizer checkpoint to match the optimizer type"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, ValueEnum)]
+pub enum ScheduleType {
+    /// Decay linearly to 1/10 over `early_stopping` epochs, then keep constant
+    #[default]
+    Linear,
+    /// Linear warmup, then cosine annealing until the last epoch
+    Cosine,
+    /// Multiply by gamma every `step_epochs` epochs
+    Step,
+    /// Cosine rise from lr / div_factor to lr, then cosine annealing to nearly 0
+    OneCycle,
+}
+
+#[derive(Config, Debug)]
+pub struct ScheduleConfig {
+    #[config(default = "ScheduleType::Linear")]
+    schedule_type: ScheduleType,
+    /// Epochs of linear warmup before cosine annealing
+    #[config(default = 1)]
+    warmup_epochs: usize,
+    /// Final learning rate of cosine annealing relative to the initial one
+    #[config(default = 0.01)]
+    min_factor: f64,
+    /// Epochs between step decays
+    #[config(default = 10)]
+    step_epochs: usize,
+    /// Factor of each step decay
+    #[config(default = 0.1)]
+    step_gamma: f64,
+    /// Proportion of the rising phase of one-cycle
+    #[config(default = 0.3)]
+    pct_start: f64,
+    /// The initial learning rate of one-cycle is lr / div_factor
+    #[config(default = 25.0)]
+    div_factor: f64,
+    /// The final learning rate of one-cycle is the initial one / final_div_factor
+    #[config(default = 1.0e4)]
+    final_div_factor: f64,
+}
+
+impl ScheduleConfig {
+    /// `linear_steps` are the steps of the linear decay, `total_steps` of the whole training
+    pub(crate) fn init(
+        &self,
+        lr: LearningRate,
+        steps_per_epoch: usize,
+        linear_steps: usize,
+        total_steps: usize,
+    ) -> Scheduler {
+        Scheduler {
+            config: self.clone(),
+            lr,
+            steps_per_epoch: steps_per_epoch.max(1),
+            linear_steps: linear_steps.max(1),
+            total_steps: total_steps.max(1),
+            step: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Scheduler {
+    config: ScheduleConfig,
+    lr: LearningRate,
+    steps_per_epoch: usize,
+    linear_steps: usize,
+    total_steps: usize,
+    step: usize,
+}
+
+impl Scheduler {
+    fn lr(&self) -> LearningRate {
+        let config = &self.config;
+        let (lr, step, total) = (self.lr, self.step as f64, self.total_steps as f64);
+        match config.schedule_type {
+            ScheduleType::Linear => lr * (1. - 0.9 * (step / self.linear_steps as f64).min(1.)),
+            ScheduleType::Cosine => {
+                let warmup = (config.warmup_epochs * self.steps_per_epoch) as f64;
+                if step < warmup {
+                    lr * step / warmup
+                } else {
+                    let progress = (step - warmup) / (total - warmup).max(1.);
+                    anneal(lr, lr * config.min_factor, progress)
+                }
+            }
+            ScheduleType::Step => {
+                let decays = self.step / (config.step_epochs * self.steps_per_epoch).max(1);
+                lr * config.step_gamma.powi(decays as i32)
+            }
+            ScheduleType::OneCycle => {
+                let rise = (total * config.pct_start).max(1.);
+                let initial = lr / config.div_factor;
+                if step < rise {
+                    anneal(initial, lr, step / rise)
+                } else {
+                    let progress = (step - rise) / (total - rise).max(1.);
+                    anneal(lr, initial / config.final_div_factor, progress)
+                }
+            }
+        }
+    }
+}
+
+/// Cosine annealing from start to end as progress goes from 0 to 1
+fn anneal(start: f64, end: f64, progress: f64) -> f64 {
+    end + (start - end) * (1. + (PI * progress.min(1.)).cos()) / 2.
+}
+
+impl LrScheduler for Scheduler {
+    type Record<B: Backend> = usize;
+
+    fn step(&mut self) -> LearningRate {
+        self.step += 1;
+        self.lr()
+    }
+
+    fn to_record<B: Backend>(&self) -> Self::Record<B> {
+        self.step
+    }
+
+    fn load_record<B: Backend>(mut self, record: Self::Record<B>) -> Self {
+        self.step = record;
+        self
+    }
+}
+
+/// The optimizer steps done by the learner, shared by `Counted` with `Accumulated`
+#[derive(Clone, Debug, Default)]
+pub(crate) struct StepCount(Arc<AtomicUsize>);
+
+impl StepCount {
+    fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, steps: usize) {
+        self.0.store(steps, Ordering::Relaxed);
+    }
+
+    fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts the steps of the inner optimizer
+pub(crate) struct Counted<O> {
+    inner: O,
+    count: StepCount,
+}
+
+impl<O> Counted<O> {
+    pub(crate) fn new(inner: O) -> Self {
+        Self {
+            inner,
+            count: StepCount::default(),
+        }
+    }
+
+    pub(crate) fn count(&self) -> StepCount {
+        self.count.clone()
+    }
+}
+
+impl<M, B, O> Optimizer<M, B> for Counted<O>
+where
+    M: AutodiffModule<B>,
+    B: AutodiffBackend,
+    O: Optimizer<M, B>,
+{
+    /// the inner record and the number of steps done
+    type Record = (O::Record, usize);
+
+    fn step(&mut self, lr: LearningRate, module: M, grads: GradientsParams) -> M {
+        let module = self.inner.step(lr, module, grads);
+        self.count.increment();
+        module
+    }
+
+    fn to_record(&self) -> Self::Record {
+        (self.inner.to_record(), self.count.get())
+    }
+
+    fn load_record(mut self, record: Self::Record) -> Self {
+        self.inner = self.inner.load_record(record.0);
+        self.count.set(record.1);
+        self
+    }
+}
+
+/// Advances the inner scheduler once per optimizer step counted by `Counted`, however many
+/// times the learner asks for the learning rate in between, e.g. once per accumulated batch
+#[derive(Clone, Debug)]
+pub(crate) struct Accumulated<S> {
+    inner: S,
+    count: StepCount,
+    /// steps of the inner scheduler, one ahead of the optimizer steps done
+    stepped: usize,
+    lr: LearningRate,
+}
+
+impl<S: LrScheduler> Accumulated<S> {
+    pub(crate) fn new(inner: S, count: StepCount) -> Self {
+        Self {
+            inner,
+            count,
+            stepped: 0,
+            lr: 0.,
+        }
+    }
+}
+
+impl<S: LrScheduler> LrScheduler for Accumulated<S> {
+    type Record<B: Backend> = (S::Record<B>, usize, LearningRate);
+
+    fn step(&mut self) -> LearningRate {
+        // the learning rate of the next optimizer step
+        while self.stepped <= self.count.get() {
+            self.lr = self.inner.step();
+            self.stepped += 1;
+        }
+        self.lr
+    }
+
+    fn to_record<B: Backend>(&self) -> Self::Record<B> {
+        (self.inner.to_record::<B>(), self.stepped, self.lr)
+    }
+
+    fn load_record<B: Backend>(mut self, record: Self::Record<B>) -> Self {
+        let (inner, stepped, lr) = record;
+        self.inner = self.inner.load_record::<B>(inner);
+        self.stepped = stepped;
+        self.lr = lr;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 10 steps per epoch, the linear decay over 50 steps and 100 steps in total
+    fn lrs(config: ScheduleConfig) -> Vec<LearningRate> {
+        let mut scheduler = config.init(1., 10, 50, 100);
+        (0..100).map(|_| scheduler.step()).collect()
+    }
+
+    fn assert_close(lr: LearningRate, expected: LearningRate) {
+        assert!((lr - expected).abs() < 1e-9, "{} != {}", lr, expected);
+    }
+
+    #[test]
+    fn linear() {
+        let lrs = lrs(ScheduleConfig::new());
+        assert_close(lrs[0], 1. - 0.9 / 50.);
+        assert_close(lrs[24], 0.55);
+        assert_close(lrs[49], 0.1);
+        assert_close(lrs[99], 0.1);
+    }
+
+    #[test]
+    fn cosine() {
+        let lrs = lrs(ScheduleConfig::new().with_schedule_type(ScheduleType::Cosine));
+        // one epoch of warmup
+        assert_close(lrs[0], 0.1);
+        assert_close(lrs[4], 0.5);
+        assert_close(lrs[9], 1.);
+        assert_close(lrs[54], 0.505);
+        assert_close(lrs[99], 0.01);
+    }
+
+    #[test]
+    fn step() {
+        let lrs = lrs(ScheduleConfig::new()
+            .with_schedule_type(ScheduleType::Step)
+            .with_step_epochs(2));
+        assert_close(lrs[18], 1.);
+        assert_close(lrs[19], 0.1);
+        assert_close(lrs[39], 0.01);
+    }
+
+    #[test]
+    fn one_cycle() {
+        let lrs = lrs(ScheduleConfig::new().with_schedule_type(ScheduleType::OneCycle));
+        // rising from 1 / 25 over 30 steps
+        assert_close(lrs[14], 0.52);
+        assert_close(lrs[29], 1.);
+        assert_close(lrs[99], 1. / 25. / 1.0e4);
+    }
+
+    #[test]
+    fn accumulated() {
+        let count = StepCount::default();
+        let mut scheduler =
+            Accumulated::new(ScheduleConfig::new().init(1., 10, 50, 100), count.clone());
+        // 3 batches per optimizer step
+        let lrs = (1..=12)
+            .map(|batch| {
+                let lr = scheduler.step();
+                if batch % 3 == 0 {
+                    count.increment();
+                }
+                lr
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(scheduler.inner.step, 4);
+        for (i, lrs) in lrs.chunks(3).enumerate() {
+            for lr in lrs {
+                assert_close(*lr, 1. - 0.9 * (i + 1) as f64 / 50.);
+            }
+        }
+    }
+
+    #[test]
+    fn accumulated_resumed() {
+        // the steps done before resuming, restored by the optimizer record
+        let count = StepCount::default();
+        let mut scheduler =
+            Accumulated::new(ScheduleConfig::new().init(1., 10, 50, 100), count.clone());
+        count.set(5);
+        assert_close(scheduler.step(), 1. - 0.9 * 6. / 50.);
+        assert_eq!(scheduler.inner.step, 6);
+    }
+}
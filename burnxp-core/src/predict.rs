@@ -1,16 +1,21 @@
 use crate::{
-    data::{ImageBatcher, ImageDataSet},
-    ModelConfig, ResNetType,
-};
-use burn::{
-    config::Config,
-    data::dataloader::DataLoaderBuilder,
-    prelude::*,
-    record::{CompactRecorder, Recorder},
+    calibrate::Thresholds,
+    data::{ImageBatch, ImageBatcher, ImageDataSet, SkipReport},
+    model::Model,
+    ModelConfig, ModelType,
 };
+use anyhow::{anyhow, Context as _, Result};
+use burn::{config::Config, data::dataloader::DataLoaderBuilder, prelude::*};
 use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt, fs::File, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+};
+use tagger::{Prediction, Viewer};
 use unicode_width::UnicodeWidthStr;
 
 #[derive(Debug, Clone, Default, ValueEnum, Serialize, Deserialize)]
@@ -19,21 +24,30 @@ pub enum Output {
     Tui,
     Tty,
     Json,
+    /// One json record per line, written as soon as each batch finishes
+    Jsonl,
+    /// One csv row per image, with a possibility column for each tag
+    Csv,
 }
 
 #[derive(Config, Debug)]
 pub struct PredictConfig {
-    model: ResNetType,
     checkpoint: PathBuf,
     input: PathBuf,
     output: Output,
     tags: PathBuf,
+    /// Overrides the backbone saved in `model_config.json`
+    model: Option<ModelType>,
+    /// Overrides the input size saved in `model_config.json`, required without it
+    size: Option<usize>,
     #[config(default = 32)]
     batch_size: usize,
     #[config(default = 8)]
     num_workers: usize,
     #[config(default = 0.5)]
     confidence_threshold: f32,
+    /// Per-tag thresholds produced by `burnxp calibrate`, overriding the confidence threshold
+    thresholds: Option<PathBuf>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -42,20 +56,28 @@ struct Tags {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Tag {
+pub(crate) struct Tag {
     name: String,
     weight: i64,
     possibility: f32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct ScoreResult {
+pub(crate) struct ScoreResult {
     score: f32,
     tags: Vec<Tag>,
 }
 
 type JsonOutput = HashMap<PathBuf, ScoreResult>;
 
+/// The line of `Output::Jsonl`, tags are all listed in sorted order
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonlRecord {
+    path: PathBuf,
+    score: f32,
+    tags: Vec<Tag>,
+}
+
 impl fmt::Display for Tag {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let width = UnicodeWidthStr::width_cjk(self.name.as_str());
@@ -71,52 +93,81 @@ impl fmt::Display for Tag {
     }
 }
 
-pub fn predict<B: Backend>(config: PredictConfig, devices: Vec<B::Device>) {
+/// Tags with weights, sorted by name as the model outputs
+pub(crate) fn load_tags(path: &Path) -> Vec<(String, i64)> {
     let all_tags: Tags = serde_json::from_reader(
-        File::open(config.tags).expect("The file containing tags and weights should be accessible"),
+        File::open(path).expect("The file containing tags and weights should be accessible"),
     )
     .expect("The file containing tags and weights should be valid");
     let mut all_tags = all_tags.tags.into_iter().collect::<Vec<_>>();
     all_tags.sort_by_key(|(k, _)| k.clone());
-    let model = ModelConfig::new(config.model)
-        .with_download(false)
+    all_tags
+}
+
+/// The possibility each tag must exceed, from the thresholds file or the confidence threshold
+pub(crate) fn load_thresholds(
+    all_tags: &[(String, i64)],
+    confidence_threshold: f32,
+    thresholds: Option<PathBuf>,
+) -> Vec<f32> {
+    match thresholds {
+        Some(path) => Thresholds::load(&path)
+            .expect("The thresholds file should be valid")
+            .for_tags(all_tags, 1. - confidence_threshold),
+        None => vec![1. - confidence_threshold; all_tags.len()],
+    }
+}
+
+pub fn predict<B: Backend>(config: PredictConfig, devices: Vec<B::Device>) -> Result<()> {
+    let all_tags = load_tags(&config.tags);
+    let thresholds = load_thresholds(&all_tags, config.confidence_threshold, config.thresholds);
+    let model_config = ModelConfig::for_checkpoint(&config.checkpoint, config.model, config.size)
+        .expect("Model config should be found");
+    let size = model_config.size;
+    let model = model_config
         .init::<B>(&devices[0], all_tags.len())
-        .load_record(
-            CompactRecorder::new()
-                .load(config.checkpoint, &devices[0])
-                .expect("Failed to load checkpoint"),
-        );
+        .load_checkpoint(config.checkpoint, &devices[0])
+        .expect("Failed to load checkpoint");
 
-    let dataloader_predict = DataLoaderBuilder::new(ImageBatcher::new())
+    let dataloader_predict = DataLoaderBuilder::new(ImageBatcher::new(size))
         .batch_size(config.batch_size)
         .num_workers(config.num_workers)
-        .build(ImageDataSet::predict(config.input).expect("Training set failed to be loaded"));
+        .build(
+            ImageDataSet::predict(config.input, size, SkipReport::new(None))
+                .expect("Training set failed to be loaded"),
+        );
 
+    let rows = dataloader_predict
+        .iter()
+        .filter(|batch| !batch.paths.is_empty())
+        .map(|batch| forward_rows(&model, batch));
     match config.output {
         Output::Tui => {
-            todo!()
+            let mut predictions = vec![];
+            for batch in rows {
+                for (path, tags) in batch? {
+                    let res = score_result(&tags, &all_tags, &thresholds);
+                    predictions.push(Prediction {
+                        path,
+                        score: res.score,
+                        tags: res
+                            .tags
+                            .into_iter()
+                            .map(|t| (t.name, t.weight, t.possibility))
+                            .collect(),
+                    });
+                }
+            }
+            Viewer::new(predictions, all_tags)
+                .run()
+                .context("Failed to run the prediction viewer")?;
         }
         Output::Tty => {
-            for batch in dataloader_predict.iter() {
-                let tags = model
-                    .forward(batch.datas)
-                    .into_data()
-                    .to_vec::<f32>()
-                    .unwrap();
-                for (path, tags) in batch.paths.into_iter().zip(tags.chunks(all_tags.len())) {
-                    let tags = tags
-                        .iter()
-                        .zip(all_tags.iter())
-                        .filter(|(p, _)| **p > 1. - config.confidence_threshold)
-                        .map(|(p, (k, v))| Tag {
-                            name: k.clone(),
-                            weight: *v,
-                            possibility: *p,
-                        })
-                        .collect::<Vec<_>>();
+            for batch in rows {
+                for (path, tags) in batch? {
+                    let tags = score_result(&tags, &all_tags, &thresholds).tags;
                     let total_score = tags
                         .iter()
-                        .filter(|t| t.possibility > 1. - config.confidence_threshold)
                         .map(|t| t.possibility * t.weight as f32)
                         .sum::<f32>();
                     println!("{}: {}", path.display(), total_score);
@@ -132,34 +183,116 @@ pub fn predict<B: Backend>(config: PredictConfig, devices: Vec<B::Device>) {
         }
         Output::Json => {
             let mut output: JsonOutput = HashMap::new();
-            for batch in dataloader_predict.iter() {
-                let tags = model
-                    .forward(batch.datas)
-                    .into_data()
-                    .to_vec::<f32>()
-                    .unwrap();
-                for (path, tags) in batch.paths.into_iter().zip(tags.chunks(all_tags.len())) {
-                    let tags = tags
-                        .iter()
-                        .zip(all_tags.iter())
-                        .filter(|(p, _)| **p > 1. - config.confidence_threshold)
-                        .map(|(p, (k, v))| Tag {
-                            name: k.clone(),
-                            weight: *v,
-                            possibility: *p,
-                        })
-                        .collect::<Vec<_>>();
-                    let total_score = tags.iter().map(|t| t.weight as f32).sum::<f32>();
-                    output.insert(
+            for batch in rows {
+                for (path, tags) in batch? {
+                    output.insert(path, score_result(&tags, &all_tags, &thresholds));
+                }
+            }
+            serde_json::to_writer_pretty(io::stdout(), &output)
+                .context("Failed to write the predictions")?;
+        }
+        Output::Jsonl => {
+            let mut stdout = io::stdout().lock();
+            for batch in rows {
+                for (path, tags) in batch? {
+                    let record = JsonlRecord {
                         path,
-                        ScoreResult {
-                            score: total_score,
-                            tags,
-                        },
-                    );
+                        score: score_result(&tags, &all_tags, &thresholds).score,
+                        tags: tags
+                            .iter()
+                            .zip(all_tags.iter())
+                            .map(|(p, (k, v))| Tag {
+                                name: k.clone(),
+                                weight: *v,
+                                possibility: *p,
+                            })
+                            .collect(),
+                    };
+                    serde_json::to_writer(&mut stdout, &record)
+                        .context("Failed to write the predictions")?;
+                    writeln!(stdout).context("Failed to write the predictions")?;
+                }
+                stdout.flush().context("Failed to write the predictions")?;
+            }
+        }
+        Output::Csv => {
+            let mut stdout = io::stdout().lock();
+            writeln!(
+                stdout,
+                "path,score,{}",
+                all_tags
+                    .iter()
+                    .map(|(k, _)| csv_escape(k))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            )
+            .context("Failed to write the predictions")?;
+            for batch in rows {
+                for (path, tags) in batch? {
+                    writeln!(
+                        stdout,
+                        "{},{},{}",
+                        csv_escape(&path.to_string_lossy()),
+                        score_result(&tags, &all_tags, &thresholds).score,
+                        tags.iter()
+                            .map(|p| p.to_string())
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    )
+                    .context("Failed to write the predictions")?;
                 }
+                stdout.flush().context("Failed to write the predictions")?;
             }
-            serde_json::to_writer_pretty(std::io::stdout(), &output).unwrap();
         }
     }
+    Ok(())
+}
+
+/// The path and possibilities of each image of a batch
+fn forward_rows<B: Backend>(
+    model: &Model<B>,
+    batch: ImageBatch<B>,
+) -> Result<Vec<(PathBuf, Vec<f32>)>> {
+    let possibilities = model.forward(batch.datas);
+    let num_classes = possibilities.dims()[1];
+    let possibilities = possibilities
+        .into_data()
+        .convert::<f32>()
+        .to_vec::<f32>()
+        .map_err(|e| anyhow!("Failed to read the possibilities of a batch: {:?}", e))?;
+    Ok(batch
+        .paths
+        .into_iter()
+        .zip(possibilities.chunks(num_classes).map(<[f32]>::to_vec))
+        .collect())
+}
+
+/// Collect the tags whose possibility is greater than its threshold and sum up their weights
+pub(crate) fn score_result(
+    possibilities: &[f32],
+    all_tags: &[(String, i64)],
+    thresholds: &[f32],
+) -> ScoreResult {
+    let tags = possibilities
+        .iter()
+        .zip(all_tags.iter())
+        .zip(thresholds)
+        .filter(|((p, _), t)| *p > *t)
+        .map(|((p, (k, v)), _)| Tag {
+            name: k.clone(),
+            weight: *v,
+            possibility: *p,
+        })
+        .collect::<Vec<_>>();
+    let score = tags.iter().map(|t| t.weight as f32).sum::<f32>();
+    ScoreResult { score, tags }
+}
+
+/// Quote the field if it contains a comma, a quote or a line break
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
 }
@@ -0,0 +1,178 @@
+use crate::{
+    data::{ImageBatcher, ImageData},
+    predict::{load_tags, load_thresholds, score_result},
+    ModelConfig, ModelType,
+};
+use anyhow::{anyhow, Result};
+use burn::{config::Config, data::dataloader::batcher::Batcher, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{Cursor, Read as _},
+    path::PathBuf,
+    sync::{mpsc::channel, Arc},
+    thread,
+    time::Duration,
+};
+use tagger::{load_image, open_image};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+#[derive(Config, Debug)]
+pub struct ServeConfig {
+    checkpoint: PathBuf,
+    tags: PathBuf,
+    /// Overrides the backbone saved in `model_config.json`
+    model: Option<ModelType>,
+    /// Overrides the input size saved in `model_config.json`, required without it
+    size: Option<usize>,
+    #[config(default = "String::from(\"127.0.0.1:8080\")")]
+    address: String,
+    /// Max number of queued images sharing one forward pass
+    #[config(default = 8)]
+    batch_size: usize,
+    /// Milliseconds to wait for more requests to fill a batch
+    #[config(default = 10)]
+    batch_wait: u64,
+    /// Number of threads handling http requests and decoding images
+    #[config(default = 4)]
+    num_workers: usize,
+    #[config(default = 0.5)]
+    confidence_threshold: f32,
+    thresholds: Option<PathBuf>,
+}
+
+/// The json body to predict an image on the disk of the server
+#[derive(Debug, Serialize, Deserialize)]
+struct PathRequest {
+    path: PathBuf,
+}
+
+/// Max size of a request body, larger images are rejected
+const MAX_BODY_SIZE: u64 = 64 << 20;
+
+/// A decoded image, answered by the model thread so that the workers keep accepting requests
+/// while the batch fills
+struct Job<B: Backend> {
+    item: ImageData<B>,
+    request: Request,
+}
+
+/// Serve `POST /predict` on localhost, the body is either the image itself
+/// or `{"path": "..."}` with the `application/json` content type
+pub fn serve<B: Backend>(config: ServeConfig, devices: Vec<B::Device>) {
+    let all_tags = load_tags(&config.tags);
+    let thresholds = load_thresholds(&all_tags, config.confidence_threshold, config.thresholds);
+    let model_config = ModelConfig::for_checkpoint(&config.checkpoint, config.model, config.size)
+        .expect("Model config should be found");
+    let size = model_config.size;
+    let model = model_config
+        .init::<B>(&devices[0], all_tags.len())
+        .load_checkpoint(config.checkpoint, &devices[0])
+        .expect("Failed to load checkpoint");
+
+    let server = Arc::new(Server::http(&config.address).expect("Failed to bind the address"));
+    println!("Serving on http://{}/predict", config.address);
+    let (queue, rx) = channel::<Job<B>>();
+    for _ in 0..config.num_workers.max(1) {
+        let server = server.clone();
+        let queue = queue.clone();
+        thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                match accept::<B>(&mut request, size) {
+                    Ok(item) => {
+                        if let Err(err) = queue.send(Job { item, request }) {
+                            let response = Response::from_string("the model stopped");
+                            err.0.request.respond(response.with_status_code(500)).ok();
+                        }
+                    }
+                    Err(response) => {
+                        request.respond(response).ok();
+                    }
+                }
+            }
+        });
+    }
+    drop(queue);
+
+    let batcher = ImageBatcher::new(size);
+    while let Ok(job) = rx.recv() {
+        let mut jobs = vec![job];
+        while jobs.len() < config.batch_size {
+            match rx.recv_timeout(Duration::from_millis(config.batch_wait)) {
+                Ok(job) => jobs.push(job),
+                Err(_) => break,
+            }
+        }
+        let (items, requests): (Vec<_>, Vec<_>) =
+            jobs.into_iter().map(|j| (j.item, j.request)).unzip();
+        let possibilities = model
+            .forward(batcher.batch(items).datas)
+            .into_data()
+            .convert::<f32>()
+            .to_vec::<f32>()
+            .unwrap();
+        let content_type =
+            Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+        for (request, p) in requests
+            .into_iter()
+            .zip(possibilities.chunks(all_tags.len()))
+        {
+            let res = score_result(p, &all_tags, &thresholds);
+            let response = Response::from_string(serde_json::to_string(&res).unwrap())
+                .with_header(content_type.clone());
+            request.respond(response).ok();
+        }
+    }
+}
+
+/// The image of a `POST /predict` request, or the error response
+fn accept<B: Backend>(
+    request: &mut Request,
+    size: usize,
+) -> Result<ImageData<B>, Response<Cursor<Vec<u8>>>> {
+    if request.url() != "/predict" {
+        return Err(Response::from_string("not found").with_status_code(404));
+    }
+    if *request.method() != Method::Post {
+        let allow = Header::from_bytes(&b"Allow"[..], &b"POST"[..]).unwrap();
+        return Err(Response::from_string("expected POST /predict")
+            .with_status_code(405)
+            .with_header(allow));
+    }
+    let too_large = || {
+        Response::from_string(format!("the body exceeds {} bytes", MAX_BODY_SIZE))
+            .with_status_code(413)
+    };
+    if request
+        .body_length()
+        .is_some_and(|len| len as u64 > MAX_BODY_SIZE)
+    {
+        return Err(too_large());
+    }
+    let bad_request = |e: anyhow::Error| Response::from_string(e.to_string()).with_status_code(400);
+    let mut body = vec![];
+    // the length may be unknown if the body is chunked
+    request
+        .as_reader()
+        .take(MAX_BODY_SIZE + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| bad_request(e.into()))?;
+    if body.len() as u64 > MAX_BODY_SIZE {
+        return Err(too_large());
+    }
+    decode(request, &body, size).map_err(bad_request)
+}
+
+/// The image of the body, the image itself or its path in json
+fn decode<B: Backend>(request: &Request, body: &[u8], size: usize) -> Result<ImageData<B>> {
+    let is_json = request
+        .headers()
+        .iter()
+        .any(|h| h.field.equiv("Content-Type") && h.value.as_str().starts_with("application/json"));
+    let (img, path) = if is_json {
+        let PathRequest { path } = serde_json::from_slice(body)?;
+        (open_image(&path)?, path)
+    } else {
+        (load_image(body)?, PathBuf::new())
+    };
+    ImageData::from_image(img, path, size).ok_or_else(|| anyhow!("invalid image"))
+}
@@ -0,0 +1,178 @@
+use crate::train::{create_artifact_dir, start, TrainingConfig};
+use burn::{config::Config, tensor::backend::AutodiffBackend};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub enum SearchType {
+    /// Every combination of the values
+    #[default]
+    Grid,
+    /// `trials` combinations drawn at random
+    Random,
+}
+
+#[derive(Config)]
+pub struct SweepConfig {
+    /// The training config shared by the trials, e.g. a `train_config.json` of a previous run
+    base: TrainingConfig,
+    /// Candidate values by field of the training config, nested fields are joined by `.`,
+    /// e.g. `learning_rate`, `model.rnn_type` or `optimizer.weight_decay`
+    params: BTreeMap<String, Vec<Value>>,
+    #[config(default = "SearchType::Grid")]
+    search: SearchType,
+    /// Number of trials of the random search
+    #[config(default = 10)]
+    trials: usize,
+    #[config(default = 42)]
+    seed: u64,
+}
+
+impl SweepConfig {
+    /// The values of each trial in the order of `params`
+    fn combinations(&self) -> Vec<Vec<Value>> {
+        for (field, values) in self.params.iter() {
+            assert!(!values.is_empty(), "expected values to sweep for {}", field);
+        }
+        match self.search {
+            SearchType::Grid => self.params.values().fold(vec![vec![]], |acc, values| {
+                acc.iter()
+                    .flat_map(|comb| {
+                        values.iter().map(move |v| {
+                            let mut comb = comb.clone();
+                            comb.push(v.clone());
+                            comb
+                        })
+                    })
+                    .collect()
+            }),
+            SearchType::Random => {
+                let mut rng = StdRng::seed_from_u64(self.seed);
+                (0..self.trials)
+                    .map(|_| {
+                        self.params
+                            .values()
+                            .map(|values| {
+                                values
+                                    .choose(&mut rng)
+                                    .cloned()
+                                    .expect("Values should not be empty")
+                            })
+                            .collect()
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// The base config with the fields overridden by the values of a trial
+    fn trial(&self, values: &[Value]) -> TrainingConfig {
+        let mut config = serde_json::to_value(&self.base).expect("Config should be serializable");
+        for (field, value) in self.params.keys().zip(values) {
+            *config
+                .pointer_mut(&format!("/{}", field.replace('.', "/")))
+                .unwrap_or_else(|| {
+                    panic!("expected {} to be a field of the training config", field)
+                }) = value.clone();
+        }
+        serde_json::from_value(config)
+            .expect("Sweep values should be valid for the training config")
+    }
+}
+
+/// Run the trials of the sweep sequentially in `artifact_dir/{i}`, then rank them by the
+/// mean average precision on the validation set in `leaderboard.txt`
+pub fn sweep<B: AutodiffBackend>(
+    artifact_dir: PathBuf,
+    config: SweepConfig,
+    devices: Vec<B::Device>,
+) {
+    create_artifact_dir(&artifact_dir);
+    config
+        .save(artifact_dir.join("sweep_config.json"))
+        .expect("Config should be saved successfully");
+
+    let combinations = config.combinations();
+    let mut leaderboard = Leaderboard {
+        fields: config.params.keys().cloned().collect(),
+        trials: vec![],
+    };
+    for (trial, values) in combinations.iter().enumerate() {
+        println!("Training trial {}/{}", trial + 1, combinations.len());
+        let report = start::<B>(
+            artifact_dir.join(trial.to_string()),
+            config.trial(values),
+            devices.clone(),
+        );
+        leaderboard.trials.push(Trial {
+            trial,
+            values: values.clone(),
+            mean_average_precision: report.mean_average_precision,
+            mean_f1: report.tags.iter().map(|t| t.f1).sum::<f32>()
+                / report.tags.len().max(1) as f32,
+        });
+        // keep the leaderboard up to date in case the sweep is interrupted
+        leaderboard.trials.sort_by(|a, b| {
+            b.mean_average_precision
+                .total_cmp(&a.mean_average_precision)
+        });
+        leaderboard
+            .save(&artifact_dir)
+            .expect("Leaderboard should be saved successfully");
+    }
+    println!("{}", leaderboard);
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Trial {
+    /// the artifact subdirectory of the trial
+    trial: usize,
+    values: Vec<Value>,
+    mean_average_precision: f32,
+    mean_f1: f32,
+}
+
+/// The trials sorted by mean average precision, best first
+#[derive(Debug, Serialize, Deserialize)]
+struct Leaderboard {
+    fields: Vec<String>,
+    trials: Vec<Trial>,
+}
+
+impl Leaderboard {
+    /// Save as `leaderboard.json` and `leaderboard.txt` in the directory
+    fn save(&self, dir: &Path) -> io::Result<()> {
+        serde_json::to_writer_pretty(File::create(dir.join("leaderboard.json"))?, self)?;
+        std::fs::write(dir.join("leaderboard.txt"), self.to_string())
+    }
+}
+
+impl fmt::Display for Leaderboard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<8}{:<8}{:<8}", "Trial", "mAP", "F1")?;
+        for field in self.fields.iter() {
+            write!(f, "{:<16}", field)?;
+        }
+        writeln!(f, "\n{:-<1$}", "", 24 + 16 * self.fields.len())?;
+        for trial in self.trials.iter() {
+            write!(
+                f,
+                "{:<8}{:<8.3}{:<8.3}",
+                trial.trial, trial.mean_average_precision, trial.mean_f1
+            )?;
+            for value in trial.values.iter() {
+                write!(f, "{:<16}", value.to_string())?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
@@ -1,9 +1,8 @@
 use burn::{
     data::dataloader::DataLoaderBuilder,
-    lr_scheduler::linear::LinearLrSchedulerConfig,
-    optim::AdamConfig,
+    module::AutodiffModule,
     prelude::*,
-    record::{CompactRecorder, Recorder},
+    record::CompactRecorder,
     tensor::backend::AutodiffBackend,
     train::{
         metric::{
@@ -13,18 +12,29 @@ use burn::{
         LearnerBuilder, MetricEarlyStoppingStrategy, StoppingCondition,
     },
 };
-use std::{fs::File, path::PathBuf};
-use tagger::DataSetDesc;
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tagger::{fold_path, DataSetDesc, Divider};
 
 use crate::{
-    data::{ImageBatcher, ImageDataSet},
+    augment::AugmentConfig,
+    cache::ImageCache,
+    data::{ImageBatcher, ImageDataSet, SkipReport},
+    ema::{BestMetric, Ema, Selection, Selector},
+    eval::{EvalReport, FoldSummary, Inference},
+    finetune::FineTune,
+    metrics::{JsonlLogger, LastInference, MetricsLog, ValidOutputs},
     model::ModelConfig,
+    optim::{Accumulated, Counted, OptimizerConfig, ScheduleConfig},
 };
 
 #[derive(Config)]
 pub struct TrainingConfig {
     model: ModelConfig,
-    optimizer: AdamConfig,
+    optimizer: OptimizerConfig,
     train_set: PathBuf,
     valid_set: PathBuf,
     pretrained: Option<PathBuf>,
@@ -34,6 +44,9 @@ pub struct TrainingConfig {
     num_epochs: usize,
     #[config(default = 1)]
     batch_size: usize,
+    /// Number of batches whose gradients are accumulated before each optimizer step
+    #[config(default = 1)]
+    grad_accumulation: usize,
     #[config(default = 1)]
     num_workers: usize,
     #[config(default = 42)]
@@ -45,27 +58,181 @@ pub struct TrainingConfig {
     early_stopping: usize,
     #[config(default = "0.5")]
     confidence_threshold: f32,
+    #[config(default = "ScheduleConfig::new()")]
+    schedule: ScheduleConfig,
+    #[config(default = "AugmentConfig::new()")]
+    augment: AugmentConfig,
+    /// Epochs training only `linear` with the backbone frozen, 0 to disable
+    #[config(default = 0)]
+    freeze_epochs: usize,
+    /// Learning rate of the backbone relative to `linear` after unfreezing
+    #[config(default = 1.0)]
+    backbone_lr_factor: f64,
+    /// Decay of the exponential moving average of the weights, 0 to disable
+    #[config(default = 0.0)]
+    ema_decay: f64,
+    /// Metric on the validation set choosing `best_model.mpk` among the raw and EMA models
+    #[config(default = "BestMetric::Hamming")]
+    best_metric: BestMetric,
+    /// Directory of the preprocessed image cache, disabled if not set
+    cache_dir: Option<PathBuf>,
+    /// Divide the tags when training starts instead of reading `train_set` and `valid_set`
+    divide: Option<DivideConfig>,
+}
+
+/// Divide the tags produced by `tagger tag` like `tagger divide`, seeded by the training seed
+#[derive(Config, Debug)]
+pub struct DivideConfig {
+    tags: PathBuf,
+    /// The ratio of the training set
+    #[config(default = 9)]
+    train: u32,
+    /// The ratio of the validation set
+    #[config(default = 1)]
+    valid: u32,
+    /// The max iter num to solve the optimization problem
+    #[config(default = 2048)]
+    max_iters: u64,
 }
 
-fn create_artifact_dir(artifact_dir: &PathBuf) {
+impl DivideConfig {
+    /// Write `train.json` and `valid.json` (suffixed by `-fold{i}` for k folds) in the directory
+    /// and return their paths
+    fn divide(&self, dir: &Path, seed: u64, folds: Option<usize>) -> (PathBuf, PathBuf) {
+        let (train_path, valid_path) = (dir.join("train.json"), dir.join("valid.json"));
+        let mut divider = Divider::new(
+            self.tags.clone(),
+            self.train,
+            self.valid,
+            train_path.clone(),
+            valid_path.clone(),
+            self.max_iters,
+        )
+        .expect("Tags file should be accessible and legal")
+        .with_seed(seed);
+        if let Some(folds) = folds {
+            divider = divider.with_folds(folds);
+        }
+        divider
+            .divide()
+            .expect("Tags should be divided successfully");
+        (train_path, valid_path)
+    }
+}
+
+pub(crate) fn create_artifact_dir(artifact_dir: &PathBuf) {
     // Remove existing artifacts before to get an accurate learner summary
     std::fs::remove_dir_all(artifact_dir).ok();
     std::fs::create_dir_all(artifact_dir).ok();
 }
 
+/// Find the epoch of the latest model checkpoint written by the file checkpointer
+fn latest_checkpoint(artifact_dir: &Path) -> Option<usize> {
+    std::fs::read_dir(artifact_dir.join("checkpoint"))
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("model-")?
+                .strip_suffix(".mpk")?
+                .parse()
+                .ok()
+        })
+        .max()
+}
+
 pub fn train<B: AutodiffBackend>(
     artifact_dir: PathBuf,
     config: TrainingConfig,
     devices: Vec<B::Device>,
+) {
+    start::<B>(artifact_dir, config, devices);
+}
+
+/// Train one model per fold in `artifact_dir/fold{i}` with the sets produced by
+/// `tagger divide --folds` or divided in the artifact directory, then summarize the
+/// evaluation reports of all the folds
+pub fn train_folds<B: AutodiffBackend>(
+    artifact_dir: PathBuf,
+    mut config: TrainingConfig,
+    folds: usize,
+    devices: Vec<B::Device>,
 ) {
     create_artifact_dir(&artifact_dir);
-    #[cfg(not(feature = "candle"))]
-    B::seed(config.seed);
+    // the folds are divided once for all
+    if let Some(divide) = config.divide.take() {
+        (config.train_set, config.valid_set) =
+            divide.divide(&artifact_dir, config.seed, Some(folds));
+    }
+    let reports = (0..folds)
+        .map(|fold| {
+            println!("Training fold {}/{}", fold + 1, folds);
+            let mut config = config.clone();
+            config.train_set = fold_path(&config.train_set, fold);
+            config.valid_set = fold_path(&config.valid_set, fold);
+            start::<B>(
+                artifact_dir.join(format!("fold{}", fold)),
+                config,
+                devices.clone(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let summary = FoldSummary::new(&reports);
+    println!("{}", summary);
+    summary
+        .save(&artifact_dir)
+        .expect("Fold summary should be saved successfully");
+}
 
+/// Train from scratch in a recreated artifact directory
+pub(crate) fn start<B: AutodiffBackend>(
+    artifact_dir: PathBuf,
+    mut config: TrainingConfig,
+    devices: Vec<B::Device>,
+) -> EvalReport {
+    create_artifact_dir(&artifact_dir);
+    // the split files are kept in the artifact directory, so resuming doesn't divide again
+    if let Some(ref divide) = config.divide {
+        (config.train_set, config.valid_set) = divide.divide(&artifact_dir, config.seed, None);
+    }
     config
         .save(artifact_dir.join("train_config.json"))
         .expect("Config should be saved successfully");
+    fit::<B>(artifact_dir, config, devices, None)
+}
+
+/// Resume training with the `train_config.json` and the latest checkpoint in the artifact directory
+pub fn resume<B: AutodiffBackend>(artifact_dir: PathBuf, devices: Vec<B::Device>) {
+    let config = TrainingConfig::load(artifact_dir.join("train_config.json"))
+        .expect("Config should be found in the artifact directory to resume from");
+    let checkpoint = latest_checkpoint(&artifact_dir);
+    match checkpoint {
+        Some(epoch) => println!("Resume training from epoch {}", epoch),
+        None => println!("No checkpoint found, training from scratch"),
+    }
+    fit::<B>(artifact_dir, config, devices, checkpoint);
+}
+
+fn fit<B: AutodiffBackend>(
+    artifact_dir: PathBuf,
+    config: TrainingConfig,
+    devices: Vec<B::Device>,
+    checkpoint: Option<usize>,
+) -> EvalReport {
+    #[cfg(not(feature = "candle"))]
+    B::seed(config.seed);
+    // also checked by the cli, but the config may come from a sweep or a resumed run
+    config
+        .augment
+        .validate()
+        .expect("Augmentation config should be valid");
+    if config.augment.mixes() && config.batch_size < 2 {
+        eprintln!("Mixup and CutMix blend the images of a batch, no-op with a batch size of 1");
+    }
 
+    let log = MetricsLog::open(&artifact_dir, &config);
     let mut train_input: DataSetDesc = serde_json::from_reader(
         File::open(config.train_set).expect("Train set file should be accessible"),
     )
@@ -77,24 +244,95 @@ pub fn train<B: AutodiffBackend>(
     .expect("Validation set file should be legal");
 
     let num_classes = train_input.num_classes;
-    let dataset_train = ImageDataSet::train(train_input).expect("Training set failed to be loaded");
-    let num_iters = dataset_train.len / config.batch_size * config.early_stopping;
-    let dataloader_train = DataLoaderBuilder::new(ImageBatcher::new())
+    let size = config.model.size;
+    let tag_names = valid_input.tags.clone();
+    let cache = config.cache_dir.clone().map(ImageCache::new);
+    let skipped = SkipReport::new(Some(artifact_dir.join("skipped.json")));
+    let dataset_train =
+        ImageDataSet::train(train_input, size, config.augment.clone(), skipped.clone())
+            .expect("Training set failed to be loaded")
+            .with_cache(cache.clone());
+    assert!(
+        config.grad_accumulation > 0,
+        "Gradients should be accumulated over at least one batch"
+    );
+    // only the horizon of the schedule and the frozen steps, the schedule itself advances with
+    // the optimizer steps counted while training, each one taking `grad_accumulation` batches
+    let steps_per_epoch =
+        (dataset_train.len.div_ceil(config.batch_size) / config.grad_accumulation).max(1);
+    let num_iters = steps_per_epoch * config.early_stopping;
+    let batcher_train = ImageBatcher::new(size).with_mix(config.augment.clone());
+    let dataloader_train = DataLoaderBuilder::new(batcher_train)
         .batch_size(config.batch_size)
         .shuffle(config.seed)
         .num_workers(config.num_workers)
         .build(dataset_train);
 
-    let dataloader_valid = DataLoaderBuilder::new(ImageBatcher::new())
+    let dataset_valid = Arc::new(
+        ImageDataSet::valid(valid_input, size, skipped.clone())
+            .expect("Validation set faild to be loaded")
+            .with_cache(cache),
+    );
+    skipped.save();
+    let dataloader_valid = DataLoaderBuilder::new(ImageBatcher::new(size))
         .batch_size(config.batch_size)
         .shuffle(config.seed)
         .num_workers(config.num_workers)
-        .build(ImageDataSet::valid(valid_input).expect("Validation set faild to be loaded"));
+        .build(dataset_valid.clone());
 
-    let learner = LearnerBuilder::new(&artifact_dir)
+    let model_config = config
+        .model
+        .with_loss_weights(loss_weights)
+        .with_download(config.download_pretrained && checkpoint.is_none());
+    model_config
+        .save(artifact_dir.join("model_config.json"))
+        .expect("Model config should be saved successfully");
+
+    // the weights will be overwritten by the checkpoint when resuming
+    let mut model = model_config
+        .init::<B>(&devices[0], num_classes)
+        .freeze(config.freeze_epochs > checkpoint.unwrap_or(0));
+    if let Some(pretrain) = config.pretrained.filter(|_| checkpoint.is_none()) {
+        model = model
+            .load_checkpoint(pretrain, &devices[0])
+            .expect("Please offer a valid pretrained model. If it's in the artifact directory, it is removed when recreating the directory.")
+    }
+    let ema = Ema::<B, _>::new(
+        FineTune::new(
+            config.optimizer.init(),
+            steps_per_epoch * config.freeze_epochs,
+            config.backbone_lr_factor,
+        ),
+        config.ema_decay,
+    );
+    let ema_weights = ema.weights();
+    let optim = Counted::new(ema);
+    let steps = optim.count();
+    let selector = Arc::new(Mutex::new(Selector::new(
+        Selection {
+            artifact_dir: artifact_dir.clone(),
+            dataset: dataset_valid,
+            size,
+            batch_size: config.batch_size,
+            num_workers: config.num_workers,
+            num_classes,
+            threshold: config.confidence_threshold,
+            metric: config.best_metric,
+        },
+        ema_weights,
+    )));
+    let last = LastInference::default();
+
+    let mut builder = LearnerBuilder::new(&artifact_dir)
         .metric_train_numeric(HammingScore::new().with_threshold(config.confidence_threshold))
         .metric_valid_numeric(HammingScore::new().with_threshold(config.confidence_threshold))
         .metric_train_numeric(LossMetric::new())
+        .metric_valid_numeric(LossMetric::new())
+        .metric_valid(ValidOutputs::new(
+            num_classes,
+            last.clone(),
+            selector.clone(),
+        ))
         .metric_train(LearningRateMetric::new())
         .metric_train(CudaMetric::new())
         .metric_train(CpuUse::new())
@@ -103,31 +341,69 @@ pub fn train<B: AutodiffBackend>(
             Aggregate::Mean,
             Direction::Highest,
             Split::Valid,
-            StoppingCondition::NoImprovementSince { n_epochs: config.early_stopping },
+            StoppingCondition::NoImprovementSince {
+                n_epochs: config.early_stopping,
+            },
         ))
+        .metric_loggers(
+            JsonlLogger::new(&artifact_dir, "train", log.clone()),
+            JsonlLogger::new(&artifact_dir, "valid", log).with_tags(
+                last.clone(),
+                tag_names.clone(),
+                config.confidence_threshold,
+            ),
+        )
         .with_file_checkpointer(CompactRecorder::new())
+        .grads_accumulation(config.grad_accumulation)
         .devices(devices.clone())
         .num_epochs(config.num_epochs)
-        .summary()
-        .build(
-            {
-                let mut model = config.model.with_loss_weights(loss_weights).with_download(config.download_pretrained).init::<B>(&devices[0], num_classes);
-                if let Some(pretrain) = config.pretrained {
-                    model = model.load_record(
-                        CompactRecorder::new()
-                            .load(pretrain, &devices[0])
-                            .expect("Please offer a valid pretrained model. If it's in the artifact directory, it is removed when recreating the directory."),
-                    )
-                }
-                model
-            },
-            config.optimizer.init(),
-            LinearLrSchedulerConfig::new(config.learning_rate, config.learning_rate/10., num_iters ).init(),
-        );
+        .summary();
+    if let Some(epoch) = checkpoint {
+        builder = builder.checkpoint(epoch);
+    }
+    let learner = builder.build(
+        model,
+        optim,
+        Accumulated::new(
+            config.schedule.init(
+                config.learning_rate,
+                steps_per_epoch,
+                num_iters,
+                steps_per_epoch * config.num_epochs,
+            ),
+            steps,
+        ),
+    );
 
-    let model_trained = learner.fit(dataloader_train, dataloader_valid);
+    let model_trained = learner.fit(dataloader_train, dataloader_valid.clone());
+    // the training images failing to load while running
+    skipped.save();
+    // the checkpoint of the last epoch is written after its validation
+    selector
+        .lock()
+        .expect("Selector should not be poisoned")
+        .finish();
 
     model_trained
+        .clone()
         .save_file(artifact_dir.join("model"), &CompactRecorder::new())
         .expect("Trained model should be saved successfully");
+
+    // validated by the learner unless no epoch was left to train
+    let inference = last.get().map_or_else(
+        || {
+            Arc::new(Inference::run(
+                &model_trained.valid(),
+                dataloader_valid,
+                num_classes,
+            ))
+        },
+        |(_, inference)| inference,
+    );
+    let report = EvalReport::new(&inference, &tag_names, config.confidence_threshold);
+    println!("{}", report);
+    report
+        .save(&artifact_dir)
+        .expect("Evaluation report should be saved successfully");
+    report
 }
@@ -0,0 +1,88 @@
+use burn::{
+    module::Param,
+    nn::{
+        conv::{Conv2d, Conv2dConfig},
+        transformer::{TransformerEncoder, TransformerEncoderConfig, TransformerEncoderInput},
+        Initializer, LayerNorm, LayerNormConfig, Linear, LinearConfig,
+    },
+    prelude::*,
+};
+
+/// A ViT-Tiny style vision transformer, trained from scratch
+#[derive(Module, Debug)]
+pub(crate) struct Vit<B: Backend> {
+    patch_embed: Conv2d<B>,
+    cls_token: Param<Tensor<B, 3>>,
+    pos_embed: Param<Tensor<B, 3>>,
+    encoder: TransformerEncoder<B>,
+    norm: LayerNorm<B>,
+    head: Linear<B>,
+}
+
+#[derive(Config, Debug)]
+pub(crate) struct VitConfig {
+    /// Input size, should be divisible by the patch size
+    size: usize,
+    num_features: usize,
+    #[config(default = 16)]
+    patch_size: usize,
+    #[config(default = 192)]
+    d_model: usize,
+    #[config(default = 12)]
+    depth: usize,
+    #[config(default = 3)]
+    n_heads: usize,
+    #[config(default = 768)]
+    d_ff: usize,
+}
+
+impl VitConfig {
+    pub(crate) fn init<B: Backend>(&self, device: &B::Device) -> Vit<B> {
+        assert!(
+            self.size % self.patch_size == 0,
+            "expected the input size to be divisible by {}",
+            self.patch_size
+        );
+        let num_patches = (self.size / self.patch_size).pow(2);
+        let initializer = Initializer::Normal {
+            mean: 0.,
+            std: 0.02,
+        };
+        Vit {
+            patch_embed: Conv2dConfig::new([3, self.d_model], [self.patch_size; 2])
+                .with_stride([self.patch_size; 2])
+                .init(device),
+            cls_token: initializer.init([1, 1, self.d_model], device),
+            pos_embed: initializer.init([1, num_patches + 1, self.d_model], device),
+            encoder: TransformerEncoderConfig::new(
+                self.d_model,
+                self.d_ff,
+                self.n_heads,
+                self.depth,
+            )
+            .with_norm_first(true)
+            .init(device),
+            norm: LayerNormConfig::new(self.d_model).init(device),
+            head: LinearConfig::new(self.d_model, self.num_features).init(device),
+        }
+    }
+}
+
+impl<B: Backend> Vit<B> {
+    /// # Shapes
+    ///   - Images [batch_size, 3, size, size]
+    ///   - Output [batch_size, num_features]
+    pub(crate) fn forward(&self, x: Tensor<B, 4>) -> Tensor<B, 2> {
+        let x = self.patch_embed.forward(x); // [batch_size, d_model, h, w]
+        let [batch_size, d_model, h, w] = x.dims();
+        let x = x.reshape([batch_size, d_model, h * w]).swap_dims(1, 2);
+        let cls = self.cls_token.val().expand([batch_size, 1, d_model]);
+        let x = Tensor::cat(vec![cls, x], 1) + self.pos_embed.val();
+        let x = self.encoder.forward(TransformerEncoderInput::new(x));
+        let x = self.norm.forward(x); // [batch_size, num_patches + 1, d_model]
+        let cls = x
+            .slice([0..batch_size, 0..1, 0..d_model])
+            .reshape([batch_size, d_model]);
+        self.head.forward(cls)
+    }
+}
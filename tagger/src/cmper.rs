@@ -4,10 +4,9 @@ use crate::matrix::Matrix;
 use crate::ordpaths::{CompareResult, OrdPaths};
 use crate::state::{CurrentScreen, PROCESS_WITH_COMPLEXITY};
 use crate::terminal::AutoDropTerminal;
-use crate::utils::{bincode_from, bincode_into, centered_rect, json_into};
+use crate::utils::{bincode_from, bincode_into, centered_rect, is_image, json_into};
 use anyhow::Result;
 use crossterm::event::{self, Event as TermEvent, KeyCode, KeyEventKind};
-use mime_guess::MimeGuess;
 use ratatui::buffer::Buffer;
 use ratatui::layout::{Constraint, Direction, Layout, Rect};
 use ratatui::widgets::{Widget, WidgetRef};
@@ -32,12 +31,10 @@ impl Cmper {
         let images = walkdir::WalkDir::new(root)
             .into_iter()
             .filter_map(|res| res.ok())
-            .filter_map(|e| match MimeGuess::from_path(e.path()).first() {
-                Some(mime) if mime.type_() == "image" => {
-                    let path = e.into_path();
-                    matrix.get_paths(&path).or(Some(OrdPaths::new([path])))
-                }
-                _ => None,
+            .filter(|e| is_image(e.path()))
+            .filter_map(|e| {
+                let path = e.into_path();
+                matrix.get_paths(&path).or(Some(OrdPaths::new([path])))
             })
             .collect::<Vec<_>>();
         PROCESS_WITH_COMPLEXITY
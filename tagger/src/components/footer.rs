@@ -197,6 +197,99 @@ impl Widget for TaggerHint {
     }
 }
 
+pub(crate) struct ViewerFooter {
+    pub current_screen: CurrentScreen,
+}
+
+impl Widget for ViewerFooter {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let chunks = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(33), Constraint::Fill(1)])
+            .split(area);
+        ViewerNavigation {
+            current_screen: self.current_screen,
+        }
+        .render(chunks[0], buf);
+        ViewerHint {
+            current_screen: self.current_screen,
+        }
+        .render(chunks[1], buf);
+    }
+}
+
+struct ViewerNavigation {
+    current_screen: CurrentScreen,
+}
+
+struct ViewerHint {
+    current_screen: CurrentScreen,
+}
+
+impl Widget for ViewerNavigation {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let current_navigation_text = vec![
+            match self.current_screen {
+                CurrentScreen::Main => Span::styled("View", Style::default().fg(Color::Cyan)),
+                CurrentScreen::Finished => {
+                    Span::styled("Finished", Style::default().fg(Color::Green))
+                }
+                CurrentScreen::Exiting => Span::styled("Exiting", Style::default().fg(Color::Red)),
+                _ => unreachable!(),
+            },
+            Span::styled(" | ", Style::default().fg(Color::White)),
+            match self.current_screen {
+                CurrentScreen::Main => Span::styled(
+                    format!(
+                        "{} page: {}/{}",
+                        *PROCESS,
+                        PROCESS.finished.load(Ordering::Relaxed) / 4,
+                        PROCESS.total.load(Ordering::Relaxed) / 4,
+                    ),
+                    Style::default().fg(Color::LightCyan),
+                ),
+                CurrentScreen::Finished => Span::styled(
+                    "All predictions viewed",
+                    Style::default().fg(Color::LightGreen),
+                ),
+                CurrentScreen::Exiting => {
+                    Span::styled("Exiting", Style::default().fg(Color::LightRed))
+                }
+                _ => unreachable!(),
+            },
+        ];
+        Paragraph::new(Line::from(current_navigation_text))
+            .block(Block::default().borders(Borders::ALL))
+            .wrap(Wrap { trim: true })
+            .render(area, buf);
+    }
+}
+
+impl Widget for ViewerHint {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let hint = {
+            match self.current_screen {
+                CurrentScreen::Main => Span::styled(
+                    "Pre(<-) Next(->) Jump(j) Filter(f) Sort(s) Quit(q)",
+                    Style::default().fg(Color::Green),
+                ),
+                CurrentScreen::Finished => {
+                    Span::styled("Press (q) to exit", Style::default().fg(Color::LightGreen))
+                }
+                CurrentScreen::Exiting => Span::styled(
+                    "Are you sure you want to exit? (y/n)",
+                    Style::default().fg(Color::LightRed),
+                ),
+                _ => unreachable!(),
+            }
+        };
+        Paragraph::new(hint)
+            .block(Block::default().borders(Borders::ALL))
+            .wrap(Wrap { trim: true })
+            .render(area, buf);
+    }
+}
+
 #[cfg(feature = "cmper")]
 pub(crate) struct CmperFooter {
     pub current_screen: CurrentScreen,
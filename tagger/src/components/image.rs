@@ -1,4 +1,7 @@
-use crate::{components::Title, utils::MyPicker};
+use crate::{
+    components::Title,
+    utils::{open_image, MyPicker},
+};
 use anyhow::{anyhow, Result};
 use crossbeam::sync::Parker;
 use lru::LruCache;
@@ -149,7 +152,7 @@ impl Image {
                     cache.put(path, line);
                 }
                 _ => {
-                    let image = image::open(&path).inspect_err(|_| u.unpark())?;
+                    let image = open_image(&path).inspect_err(|_| u.unpark())?;
                     let mut image_fit_state = {
                         let mut picker = PICKER.write().map_err(|_| anyhow!(PICKER_ERR))?;
                         picker.new_resize_protocol(image)
@@ -209,7 +212,7 @@ fn preload(path: PathBuf, chunk: Rect) {
                 cache.put(path, line);
             }
             None => {
-                let image = image::open(&path).inspect_err(|_| u.unpark())?;
+                let image = open_image(&path).inspect_err(|_| u.unpark())?;
                 let mut image_fit_state = {
                     let mut picker = PICKER.write().map_err(|_| anyhow!(PICKER_ERR))?;
                     picker.new_resize_protocol(image)
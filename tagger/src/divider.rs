@@ -1,13 +1,16 @@
 use crate::utils::{json_from, json_into, BitFlags, DataSetDesc, TagRecord};
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use argmin::{
     core::{observers::ObserverMode, CostFunction, Executor, Gradient},
     solver::{gradientdescent::SteepestDescent, linesearch::HagerZhangLineSearch},
 };
 use argmin_observer_slog::SlogLogger;
 use nalgebra::{DMatrix, DVector};
-use rand::{distributions::Uniform, seq::SliceRandom, thread_rng, Rng};
-use std::{collections::HashMap, path::PathBuf};
+use rand::{distributions::Uniform, rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 use unicode_width::UnicodeWidthStr;
 
 #[derive(Debug)]
@@ -17,6 +20,9 @@ pub struct Divider {
     train_path: PathBuf,
     valid_path: PathBuf,
     max_iters: u64,
+    /// Number of folds for cross-validation, the ratio is ignored if set
+    folds: Option<usize>,
+    seed: Option<u64>,
 }
 
 impl Divider {
@@ -28,42 +34,123 @@ impl Divider {
         valid_path: PathBuf,
         max_iters: u64,
     ) -> Result<Self> {
-        assert!(train + valid > 0, "expected train + valid > 0");
         Ok(Self {
             to_divide: json_from(&path)?,
             ratio: (train, valid),
             train_path,
             valid_path,
             max_iters,
+            folds: None,
+            seed: None,
         })
     }
 
+    /// Divide into k folds instead, the i-th fold is written to the train and valid paths
+    /// suffixed by `-fold{i}`, see [`fold_path`]
+    pub fn with_folds(mut self, folds: usize) -> Self {
+        self.folds = Some(folds);
+        self
+    }
+
+    /// Seed the shuffling and the solver, so that the same tags are always divided the same way
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
     pub fn divide(self) -> Result<()> {
-        let mut all_tags = self.to_divide.tags.keys().collect::<Vec<_>>();
+        match self.folds {
+            Some(folds) => ensure!(folds > 1, "expected at least 2 folds, got {}", folds),
+            None => ensure!(
+                self.ratio.0 + self.ratio.1 > 0,
+                "expected train + valid > 0"
+            ),
+        }
+        let mut all_tags = self.to_divide.tags.keys().cloned().collect::<Vec<_>>();
         all_tags.sort();
-        let num_classes = all_tags.len();
-        let mut train_set = DataSetDesc::new(num_classes);
-        let mut valid_set = DataSetDesc::new(num_classes);
-        let mut rng = thread_rng();
+        let mut rng = match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
         let mut map_v: HashMap<BitFlags, Vec<PathBuf>> = HashMap::new();
-        for (path, tags) in self.to_divide.tagged {
+        for (path, tags) in self.to_divide.tagged.iter() {
             let mut flags = BitFlags::default();
             for (i, tag) in all_tags.iter().enumerate() {
                 if tags.contains(tag) {
                     flags.enable(i as u64);
                 }
             }
-            map_v.entry(flags).or_default().push(path);
+            map_v.entry(flags).or_default().push(path.clone());
         }
-        let mut map_t = HashMap::new();
-        for (flags, paths) in map_v.iter_mut() {
+        let mut groups = map_v.iter_mut().collect::<Vec<_>>();
+        // shuffle in a fixed order, so that the seed reproduces the division
+        groups.sort_by_key(|(flags, _)| Vec::<u8>::from(**flags));
+        for (_, paths) in groups {
+            paths.sort();
             paths.shuffle(&mut rng);
-            let split =
-                self.ratio.1 as usize * paths.len() / (self.ratio.0 + self.ratio.1) as usize;
-            let train = paths.drain(split..).collect::<Vec<_>>();
-            map_t.insert(*flags, train);
         }
-        let all_flags = map_t.keys().cloned().collect::<Vec<_>>();
+        match self.folds {
+            None => {
+                let mut map_t = HashMap::new();
+                for (flags, paths) in map_v.iter_mut() {
+                    let split = self.ratio.1 as usize * paths.len()
+                        / (self.ratio.0 + self.ratio.1) as usize;
+                    let train = paths.drain(split..).collect::<Vec<_>>();
+                    map_t.insert(*flags, train);
+                }
+                let (train_set, valid_set) = self.data_sets(&all_tags, map_t, map_v, &mut rng)?;
+                json_into(&self.train_path, &train_set)?;
+                json_into(&self.valid_path, &valid_set)?;
+            }
+            Some(folds) => {
+                for fold in 0..folds {
+                    println!("Fold {}/{}", fold + 1, folds);
+                    // each combination of tags is spread evenly over the folds, dealt round-robin
+                    // across the combinations, so that the small ones do not all land in the
+                    // first fold
+                    let (mut map_t, mut map_f) = (HashMap::new(), HashMap::new());
+                    let mut groups = map_v.iter().collect::<Vec<_>>();
+                    groups.sort_by_key(|(flags, _)| Vec::<u8>::from(**flags));
+                    let mut i = 0;
+                    for (flags, paths) in groups {
+                        for path in paths {
+                            let map = if i % folds == fold {
+                                &mut map_f
+                            } else {
+                                &mut map_t
+                            };
+                            map.entry(*flags)
+                                .or_insert_with(Vec::new)
+                                .push(path.clone());
+                            i += 1;
+                        }
+                    }
+                    let (train_set, valid_set) =
+                        self.data_sets(&all_tags, map_t, map_f, &mut rng)?;
+                    json_into(&fold_path(&self.train_path, fold), &train_set)?;
+                    json_into(&fold_path(&self.valid_path, fold), &valid_set)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Solve the up sampling and loss weights of the training set to balance the tags
+    fn data_sets(
+        &self,
+        all_tags: &[String],
+        mut map_t: HashMap<BitFlags, Vec<PathBuf>>,
+        mut map_v: HashMap<BitFlags, Vec<PathBuf>>,
+        rng: &mut impl Rng,
+    ) -> Result<(DataSetDesc, DataSetDesc)> {
+        // a combination of tags without training images can not be up sampled
+        map_t.retain(|_, paths| !paths.is_empty());
+        map_v.retain(|_, paths| !paths.is_empty());
+        let num_classes = all_tags.len();
+        let mut train_set = DataSetDesc::new(num_classes);
+        let mut valid_set = DataSetDesc::new(num_classes);
+        let mut all_flags = map_t.keys().cloned().collect::<Vec<_>>();
+        all_flags.sort_by_key(|flags| Vec::<u8>::from(*flags));
         let flags = all_flags
             .iter()
             .map(|k| {
@@ -116,14 +203,25 @@ impl Divider {
             )
         }
 
+        train_set.tags = all_tags.to_vec();
+        valid_set.tags = train_set.tags.clone();
         train_set.up_sample = all_flags.into_iter().zip(ans).collect();
         train_set.loss_weights = Some(weights.into_iter().map(|x| *x as f32).collect());
         train_set.binary_encodings = map_t;
         valid_set.binary_encodings = map_v;
-        json_into(&self.train_path, &train_set)?;
-        json_into(&self.valid_path, &valid_set)?;
-        Ok(())
+        Ok((train_set, valid_set))
+    }
+}
+
+/// The path of the i-th fold, e.g. `train.json` -> `train-fold0.json`
+pub fn fold_path(path: &Path, fold: usize) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!("-fold{}", fold));
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
     }
+    path.with_file_name(name)
 }
 
 struct Problem {
@@ -15,12 +15,14 @@ mod state;
 mod tagger;
 mod terminal;
 mod utils;
+mod viewer;
 
 #[cfg(feature = "cmper")]
 pub use cmper::Cmper;
-pub use divider::Divider;
+pub use divider::{fold_path, Divider};
 #[cfg(feature = "observer")]
 pub use observer::Observer;
 pub use picker::{Method, Picker};
 pub use tagger::Tagger;
-pub use utils::{BitFlags, DataSetDesc};
+pub use utils::{is_image, load_image, open_image, BitFlags, DataSetDesc};
+pub use viewer::{Prediction, Viewer};
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use clap::{CommandFactory as _, Parser, Subcommand};
+use clap::{builder::RangedU64ValueParser, CommandFactory as _, Parser, Subcommand};
 use clap_complete::{generate, Shell};
 use std::path::PathBuf;
 #[cfg(feature = "cmper")]
@@ -55,6 +55,13 @@ enum SubCmd {
         /// The max iter num to solve the optimization problem
         #[clap(short, long, default_value = "2048")]
         max_iters: u64,
+        /// Divide into k folds for cross-validation instead, ignoring the ratio.
+        /// The i-th fold is written to the paths suffixed by `-fold{i}`, e.g. `train-fold0.json`
+        #[clap(short, long, value_parser = RangedU64ValueParser::<usize>::new().range(2..))]
+        folds: Option<usize>,
+        /// Random seed to reproduce the division
+        #[clap(long)]
+        seed: Option<u64>,
         /// The path to the scores produced by the tagger tag/cmp subcommand
         path: PathBuf,
     },
@@ -105,9 +112,17 @@ fn main() -> Result<()> {
             train_path,
             valid_path,
             max_iters,
+            folds,
+            seed,
             path,
         } => {
-            let divider = Divider::new(path, train, valid, train_path, valid_path, max_iters)?;
+            let mut divider = Divider::new(path, train, valid, train_path, valid_path, max_iters)?;
+            if let Some(folds) = folds {
+                divider = divider.with_folds(folds);
+            }
+            if let Some(seed) = seed {
+                divider = divider.with_seed(seed);
+            }
             divider.divide()?;
         }
         SubCmd::GenCompletion { shell } => {
@@ -1,5 +1,4 @@
-use image::DynamicImage;
-use mime_guess::MimeGuess;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, ImageResult};
 use ratatui::layout::{Constraint, Direction, Layout, Rect};
 use ratatui_image::picker::Picker;
 use ratatui_image::protocol::StatefulProtocol;
@@ -11,7 +10,7 @@ use std::{
     collections::HashMap,
     fs::File,
     hash::Hash,
-    io,
+    io::{self, BufRead, Cursor, Seek},
     path::{Path, PathBuf},
 };
 
@@ -69,10 +68,8 @@ pub(crate) fn images_walk(root: impl AsRef<Path>) -> Vec<PathBuf> {
     let mut res = walkdir::WalkDir::new(root)
         .into_iter()
         .filter_map(|res| res.ok())
-        .filter_map(|e| match MimeGuess::from_path(e.path()).first() {
-            Some(mime) if mime.type_() == "image" => Some(e.into_path()),
-            _ => None,
-        })
+        .filter(|e| is_image(e.path()))
+        .map(|e| e.into_path())
         .collect::<Vec<_>>();
     res.sort_by_key(|p| {
         p.metadata()
@@ -83,6 +80,34 @@ pub(crate) fn images_walk(root: impl AsRef<Path>) -> Vec<PathBuf> {
     res
 }
 
+/// Whether the file is an image of a format which can be decoded, judging by its extension
+pub fn is_image(path: &Path) -> bool {
+    match ImageFormat::from_path(path) {
+        // image decodes AVIF only with its `avif-native` feature, enabled by our `avif`
+        Ok(ImageFormat::Avif) => cfg!(feature = "avif"),
+        Ok(format) => format.reading_enabled(),
+        Err(_) => false,
+    }
+}
+
+/// Open an image, rotated and flipped as told by its EXIF orientation
+pub fn open_image(path: impl AsRef<Path>) -> ImageResult<DynamicImage> {
+    decode_oriented(ImageReader::open(path)?)
+}
+
+/// Load an image from memory, rotated and flipped as told by its EXIF orientation
+pub fn load_image(buf: &[u8]) -> ImageResult<DynamicImage> {
+    decode_oriented(ImageReader::new(Cursor::new(buf)))
+}
+
+fn decode_oriented<R: BufRead + Seek>(reader: ImageReader<R>) -> ImageResult<DynamicImage> {
+    let mut decoder = reader.with_guessed_format()?.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
 fn picker() -> ratatui_image::picker::Picker {
     #[cfg(unix)]
     let mut picker = ratatui_image::picker::Picker::from_termios()
@@ -421,6 +446,9 @@ impl<const N: usize> fmt::Display for InputBuffer<N> {
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct DataSetDesc {
     pub num_classes: usize,
+    /// tag names in the order of the bits in `BitFlags`
+    #[serde(default)]
+    pub tags: Vec<String>,
     pub loss_weights: Option<Vec<f32>>,
     pub up_sample: HashMap<BitFlags, usize>,
     pub binary_encodings: HashMap<BitFlags, Vec<PathBuf>>,
@@ -430,6 +458,7 @@ impl DataSetDesc {
     pub fn new(num_classes: usize) -> Self {
         Self {
             num_classes,
+            tags: vec![],
             loss_weights: None,
             up_sample: HashMap::new(),
             binary_encodings: HashMap::new(),
@@ -0,0 +1,310 @@
+use crate::{
+    components::{Grid, Input, Quit, TagGrid, Title, ViewerFooter},
+    state::{CurrentScreen, PROCESS},
+    terminal::AutoDropTerminal,
+    utils::{centered_rect, Items, Tag},
+};
+use anyhow::Result;
+use crossterm::event::{self, Event as TermEvent, KeyCode, KeyEventKind};
+use ratatui::{
+    buffer::Buffer,
+    layout::{Constraint, Direction, Layout, Rect},
+    widgets::{Widget, WidgetRef},
+};
+use std::sync::atomic::Ordering;
+use std::{collections::HashMap, fmt, path::PathBuf};
+
+/// The prediction of one image
+#[derive(Debug, Clone)]
+pub struct Prediction {
+    pub path: PathBuf,
+    pub score: f32,
+    /// (name, weight, possibility)
+    pub tags: Vec<(String, i64, f32)>,
+}
+
+impl Prediction {
+    fn possibility(&self, tag: &str) -> f32 {
+        self.tags
+            .iter()
+            .find(|(name, _, _)| name == tag)
+            .map_or(0., |(_, _, p)| *p)
+    }
+}
+
+impl fmt::Display for Prediction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.2} {}",
+            self.score,
+            self.tags
+                .iter()
+                .map(|(name, weight, p)| format!("{}({},{:.2})", name, weight, p))
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum SortBy {
+    #[default]
+    ScoreDesc,
+    ScoreAsc,
+    /// possibility of the tag filtered by
+    Possibility,
+}
+
+impl fmt::Display for SortBy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SortBy::ScoreDesc => write!(f, "Score↓"),
+            SortBy::ScoreAsc => write!(f, "Score↑"),
+            SortBy::Possibility => write!(f, "Possibility↓"),
+        }
+    }
+}
+
+pub struct Viewer {
+    current_screen: CurrentScreen,
+    predictions: HashMap<PathBuf, Prediction>,
+    items: Items<PathBuf, 4>,
+    tags: Items<Tag, 9>,
+    filter: Option<Tag>,
+    sort_by: SortBy,
+}
+
+impl Viewer {
+    /// `tags` are all the tags with their weights
+    pub fn new(predictions: Vec<Prediction>, tags: Vec<(String, i64)>) -> Self {
+        let mut tags = tags
+            .iter()
+            .map(|(name, score)| (name, score).into())
+            .collect::<Vec<Tag>>();
+        tags.sort_unstable();
+        tags.reverse();
+        let mut viewer = Self {
+            current_screen: CurrentScreen::Main,
+            predictions: predictions
+                .into_iter()
+                .map(|p| (p.path.clone(), p))
+                .collect(),
+            items: Items::default(),
+            tags: Items::new(tags),
+            filter: None,
+            sort_by: SortBy::default(),
+        };
+        viewer.refresh();
+        viewer
+    }
+
+    /// Filter and sort the predictions, then go back to the first page
+    fn refresh(&mut self) {
+        let mut items = self
+            .predictions
+            .values()
+            .filter(|p| match &self.filter {
+                Some(tag) => p.tags.iter().any(|(name, _, _)| *name == tag.name),
+                None => true,
+            })
+            .collect::<Vec<_>>();
+        items.sort_by(|a, b| {
+            match (self.sort_by, &self.filter) {
+                (SortBy::ScoreAsc, _) => a.score.total_cmp(&b.score),
+                (SortBy::Possibility, Some(tag)) => b
+                    .possibility(&tag.name)
+                    .total_cmp(&a.possibility(&tag.name)),
+                _ => b.score.total_cmp(&a.score),
+            }
+            .then_with(|| a.path.cmp(&b.path))
+        });
+        PROCESS.total.store(items.len(), Ordering::Relaxed);
+        self.items = Items::new(items.into_iter().map(|p| p.path.clone()).collect());
+    }
+
+    fn next_sort(&mut self) {
+        self.sort_by = match self.sort_by {
+            SortBy::ScoreDesc => SortBy::ScoreAsc,
+            SortBy::ScoreAsc if self.filter.is_some() => SortBy::Possibility,
+            _ => SortBy::ScoreDesc,
+        };
+        self.refresh();
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        let mut terminal = AutoDropTerminal::new()?;
+        loop {
+            PROCESS
+                .finished
+                .store(4 * self.items.page(), Ordering::Relaxed);
+
+            'l: loop {
+                terminal.draw(|f| {
+                    f.render_widget(&*self, f.area());
+                })?;
+                while let TermEvent::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Release {
+                        // Skip events that are not KeyEventKind::Press
+                        continue;
+                    }
+                    match self.current_screen {
+                        CurrentScreen::Main => match key.code {
+                            KeyCode::Char('q') => self.current_screen = CurrentScreen::Exiting,
+                            KeyCode::Char('j') => self.current_screen = CurrentScreen::Popup(0), // page jump
+                            KeyCode::Char('f') => self.current_screen = CurrentScreen::Popup(1), // filter by tag
+                            KeyCode::Char('s') => {
+                                self.next_sort();
+                                break 'l;
+                            }
+                            KeyCode::Enter | KeyCode::Right | KeyCode::Down => {
+                                if self.items.inc_page() {
+                                    self.current_screen = CurrentScreen::Finished;
+                                    break;
+                                }
+                                break 'l;
+                            }
+                            KeyCode::Left | KeyCode::Up => {
+                                self.items.dec_page();
+                                break 'l;
+                            }
+                            _ => continue,
+                        },
+                        // page jump
+                        CurrentScreen::Popup(0) => match key.code {
+                            KeyCode::Char(c) if c.is_numeric() => {
+                                self.items.set_page(
+                                    self.items.page() * 10 + c.to_digit(10).unwrap() as usize,
+                                );
+                            }
+                            KeyCode::Backspace => self.items.set_page(self.items.page() / 10),
+                            KeyCode::Enter => {
+                                self.current_screen = CurrentScreen::Main;
+                                break 'l;
+                            }
+                            _ => continue,
+                        },
+                        // filter by tag
+                        CurrentScreen::Popup(1) => match key.code {
+                            KeyCode::Char(c) if c.is_numeric() => {
+                                let tags = self.tags.current_items();
+                                let i = c.to_digit(10).unwrap() as usize;
+                                if i > 0 && i <= tags.len() {
+                                    self.filter = Some(tags[i - 1].clone());
+                                    self.refresh();
+                                }
+                                self.current_screen = CurrentScreen::Main;
+                                break 'l;
+                            }
+                            KeyCode::Backspace => {
+                                self.filter = None;
+                                if self.sort_by == SortBy::Possibility {
+                                    self.sort_by = SortBy::ScoreDesc;
+                                }
+                                self.refresh();
+                                self.current_screen = CurrentScreen::Main;
+                                break 'l;
+                            }
+                            KeyCode::Up | KeyCode::Left => {
+                                if self.tags.dec_page() {
+                                    self.tags.set_page(usize::MAX);
+                                }
+                            }
+                            KeyCode::Down | KeyCode::Right => {
+                                if self.tags.inc_page() {
+                                    self.tags.set_page(0);
+                                }
+                            }
+                            KeyCode::Esc => self.current_screen = CurrentScreen::Main,
+                            _ => continue,
+                        },
+                        CurrentScreen::Popup(_) => unreachable!(),
+                        CurrentScreen::Finished => match key.code {
+                            KeyCode::Char('q') => self.current_screen = CurrentScreen::Exiting,
+                            _ => self.current_screen = CurrentScreen::Main,
+                        },
+                        CurrentScreen::Exiting => match key.code {
+                            KeyCode::Char('y') | KeyCode::Char('Y') => return Ok(()),
+                            _ => self.current_screen = CurrentScreen::Main,
+                        },
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl WidgetRef for Viewer {
+    fn render_ref(&self, area: Rect, buf: &mut Buffer) {
+        if CurrentScreen::Exiting == self.current_screen {
+            let area = centered_rect(60, 25, area);
+            Quit.render(area, buf);
+            return;
+        }
+        if let CurrentScreen::Popup(mode) = self.current_screen {
+            let area = centered_rect(60, 30, area);
+            match mode {
+                // page jump
+                0 => Input::new("Page to go", self.items.page()).render(area, buf),
+                // filter by tag
+                1 => TagGrid::<'_, Tag, 3, 3>::new(self.tags.current_items()).render(area, buf),
+                _ => unreachable!(),
+            }
+            return;
+        }
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(3),
+                Constraint::Min(1),
+                Constraint::Length(3),
+            ])
+            .split(area);
+        {
+            let chunks = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Ratio(1, 2); 2])
+                .split(chunks[0]);
+            Title {
+                title: format!("Viewer | Sort: {}", self.sort_by),
+            }
+            .render(chunks[0], buf);
+            Title {
+                title: match &self.filter {
+                    Some(tag) => format!("Filter: {}", tag),
+                    None => "Press f to filter by tag".to_string(),
+                },
+            }
+            .render(chunks[1], buf);
+        }
+        if CurrentScreen::Main == self.current_screen {
+            Grid::<'_, 2, 2, 4>::new(
+                self.items.current_items(),
+                self.items.preload_items(),
+                [false; 4],
+                (0..4)
+                    .map(|i| {
+                        self.items
+                            .current_items()
+                            .get(i)
+                            .and_then(|item| self.predictions.get(item))
+                            .map(|p| p.to_string())
+                    })
+                    .collect::<Vec<_>>()
+                    .try_into()
+                    .ok(),
+            )
+            .render(chunks[1], buf);
+        } else {
+            Title {
+                title: "The viewing finished.".to_string(),
+            }
+            .render(chunks[1], buf);
+        }
+        ViewerFooter {
+            current_screen: self.current_screen,
+        }
+        .render(chunks[2], buf);
+    }
+}